@@ -0,0 +1,17 @@
+-- Films which have been fully uploaded and verified
+CREATE TABLE films (
+    team VARCHAR(7) PRIMARY KEY REFERENCES teams (id),
+    file_name TEXT NOT NULL,
+    "size" BIGINT NOT NULL,
+    checksum TEXT NOT NULL,
+    uploaded_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+-- Uploads which are still in progress, one per team
+CREATE TABLE film_uploads (
+    team VARCHAR(7) PRIMARY KEY REFERENCES teams (id),
+    file_name TEXT NOT NULL,
+    "size" BIGINT NOT NULL,
+    checksum TEXT NOT NULL,
+    received BIGINT NOT NULL DEFAULT 0,
+    started_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
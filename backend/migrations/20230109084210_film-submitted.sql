@@ -0,0 +1,3 @@
+-- Teams mark their entry as final once the film and its details are ready
+ALTER TABLE teams
+ADD COLUMN submitted BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,9 @@
+-- Details read out of the film's container when the upload finishes
+ALTER TABLE films
+ADD COLUMN container TEXT,
+    ADD COLUMN duration DOUBLE PRECISION,
+    ADD COLUMN width INTEGER,
+    ADD COLUMN height INTEGER,
+    ADD COLUMN video_codec TEXT,
+    ADD COLUMN frame_rate DOUBLE PRECISION,
+    ADD COLUMN overlength BOOLEAN NOT NULL DEFAULT false;
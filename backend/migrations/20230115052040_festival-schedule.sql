@@ -0,0 +1,15 @@
+-- Festival wide dates, there is only ever one row. A NULL date isn't enforced.
+CREATE TABLE festival_schedule (
+    id BOOLEAN PRIMARY KEY DEFAULT true CHECK (id),
+    registration_opens TIMESTAMPTZ,
+    registration_closes TIMESTAMPTZ,
+    submission_deadline TIMESTAMPTZ,
+    edits_lock TIMESTAMPTZ
+);
+INSERT INTO festival_schedule DEFAULT VALUES;
+-- Extensions given to individual teams, these replace the festival wide dates
+CREATE TABLE deadline_extensions (
+    team VARCHAR(7) PRIMARY KEY REFERENCES teams (id),
+    submission_deadline TIMESTAMPTZ,
+    edits_lock TIMESTAMPTZ
+);
@@ -0,0 +1,12 @@
+-- The divisions films are entered in, e.g. junior, senior and open
+CREATE TABLE categories (
+    id TEXT PRIMARY KEY,
+    "name" TEXT NOT NULL,
+    description TEXT NOT NULL DEFAULT '',
+    -- Eligibility rules shown to teams when they pick a category
+    rules TEXT NOT NULL DEFAULT '',
+    -- When set, everyone in the team needs an email address at this domain
+    email_domain TEXT
+);
+ALTER TABLE teams
+ADD COLUMN category TEXT REFERENCES categories (id);
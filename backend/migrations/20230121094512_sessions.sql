@@ -0,0 +1,14 @@
+-- Logins kept on the server, the browser only holds a random token whose hash is stored here
+CREATE TABLE sessions (
+    id BIGSERIAL PRIMARY KEY,
+    token_hash TEXT NOT NULL UNIQUE,
+    "user" TEXT NOT NULL REFERENCES users (id),
+    id_token TEXT NOT NULL,
+    id_token_expires TIMESTAMPTZ NOT NULL,
+    -- Not every provider gives out refresh tokens, without one the session ends with the id token
+    refresh_token TEXT,
+    created TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_used TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX sessions_user ON sessions ("user");
@@ -0,0 +1,37 @@
+-- What a role lets its users do, checked by the API
+CREATE TYPE permission AS ENUM (
+    -- See every team, its members and its film
+    'view_teams',
+    -- Change any team's category, film details and deadline extensions
+    'edit_teams',
+    -- Change the schedule and categories
+    'manage_festival',
+    -- Assign roles and log people out
+    'manage_users'
+);
+-- The committee's jobs, e.g. moderator, judge and organiser
+CREATE TABLE roles (
+    id TEXT PRIMARY KEY,
+    "name" TEXT NOT NULL,
+    permissions permission [] NOT NULL DEFAULT '{}'
+);
+CREATE TABLE user_roles (
+    "user" TEXT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    "role" TEXT NOT NULL REFERENCES roles (id) ON DELETE CASCADE,
+    PRIMARY KEY ("user", "role")
+);
+INSERT INTO roles (id, "name", permissions)
+VALUES (
+        'organiser',
+        'Organiser',
+        '{view_teams, edit_teams, manage_festival, manage_users}'
+    ),
+    ('moderator', 'Moderator', '{view_teams, edit_teams}'),
+    ('judge', 'Judge', '{view_teams}');
+-- Admins could do everything
+INSERT INTO user_roles ("user", "role")
+SELECT id,
+    'organiser'
+FROM users
+WHERE is_admin;
+ALTER TABLE users DROP COLUMN is_admin;
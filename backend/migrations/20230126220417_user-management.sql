@@ -0,0 +1,14 @@
+-- Disabled users can't log in or use the API
+ALTER TABLE users
+ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT false;
+-- Every change made to users and roles, and who made it
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    actor TEXT NOT NULL REFERENCES users (id),
+    "action" TEXT NOT NULL,
+    -- The id of the user or role which was changed
+    subject TEXT NOT NULL,
+    details JSONB NOT NULL DEFAULT '{}',
+    created TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX audit_log_subject ON audit_log (subject);
@@ -0,0 +1,12 @@
+-- The member who can remove others and hand the team over, NULL only while the team is empty
+ALTER TABLE teams
+ADD COLUMN captain TEXT REFERENCES users (id);
+-- Whoever has been in each team the longest, usually the person who made it
+UPDATE teams
+SET captain = (
+        SELECT "user"
+        FROM user_connection
+        WHERE team = teams.id
+        ORDER BY id
+        LIMIT 1
+    );
@@ -0,0 +1,16 @@
+-- The codes people use to join a team, separate from its id so they can be replaced. A team has at
+-- most one, and none once it has been revoked or a single use code has been used.
+CREATE TABLE join_codes (
+    code TEXT PRIMARY KEY,
+    team VARCHAR(7) NOT NULL UNIQUE REFERENCES teams (id) ON DELETE CASCADE,
+    -- The code stops working after this
+    expires TIMESTAMPTZ,
+    -- The code stops working once someone has joined with it
+    single_use BOOLEAN NOT NULL DEFAULT false,
+    created TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+-- Teams' ids were their codes, so the codes already handed out keep working
+INSERT INTO join_codes (code, team)
+SELECT id,
+    id
+FROM teams;
@@ -0,0 +1,6 @@
+-- The most people a team can have. The festival wide limit applies unless the team's category has
+-- its own, and NULL means there's no limit.
+ALTER TABLE festival_schedule
+ADD COLUMN max_team_size INTEGER CHECK (max_team_size > 0);
+ALTER TABLE categories
+ADD COLUMN max_team_size INTEGER CHECK (max_team_size > 0);
@@ -0,0 +1,21 @@
+-- Nothing stopped people joining two teams at once, so they're left in the one they joined first
+DELETE FROM user_connection a USING user_connection b
+WHERE a."user" = b."user"
+    AND a.id > b.id;
+-- Teams whose captain was taken out go to whoever has been in them the longest
+UPDATE teams
+SET captain = (
+        SELECT "user"
+        FROM user_connection
+        WHERE team = teams.id
+        ORDER BY id
+        LIMIT 1
+    )
+WHERE NOT exists(
+        SELECT 1
+        FROM user_connection
+        WHERE team = teams.id
+            AND "user" = teams.captain
+    );
+ALTER TABLE user_connection
+ADD CONSTRAINT user_connection_user_key UNIQUE ("user");
@@ -0,0 +1,28 @@
+-- Teams which have been empty for a while are archived, which frees up their name and stops
+-- anyone joining them. Admins can restore them.
+ALTER TABLE teams
+ADD COLUMN emptied TIMESTAMPTZ,
+    ADD COLUMN archived TIMESTAMPTZ;
+-- Teams which are already empty get the full grace period from now
+UPDATE teams
+SET emptied = now()
+WHERE NOT exists(
+        SELECT 1
+        FROM user_connection
+        WHERE team = teams.id
+    );
+-- Archived teams' names can be used again
+DROP INDEX user_connection_name_idx;
+CREATE UNIQUE INDEX teams_name_idx ON teams (lower("name"))
+WHERE archived IS NULL;
+-- Everyone who has joined or left a team, and who made it happen
+CREATE TABLE membership_changes (
+    id BIGSERIAL PRIMARY KEY,
+    team VARCHAR(7) NOT NULL REFERENCES teams (id) ON DELETE CASCADE,
+    "user" TEXT NOT NULL REFERENCES users (id),
+    joined BOOLEAN NOT NULL,
+    -- The user themselves, their captain or an admin
+    actor TEXT NOT NULL REFERENCES users (id),
+    created TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX membership_changes_team ON membership_changes (team);
@@ -0,0 +1,11 @@
+-- Teams can have their captain approve everyone who joins with their code
+ALTER TABLE teams
+ADD COLUMN approval_required BOOLEAN NOT NULL DEFAULT false;
+-- People waiting for a captain to let them in. They can only be waiting on one team at a time,
+-- like they can only be in one.
+CREATE TABLE join_requests (
+    "user" TEXT PRIMARY KEY REFERENCES users (id),
+    team VARCHAR(7) NOT NULL REFERENCES teams (id) ON DELETE CASCADE,
+    created TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX join_requests_team ON join_requests (team);
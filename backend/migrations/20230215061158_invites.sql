@@ -0,0 +1,14 @@
+-- Emails captains have sent inviting people to their team. The link in the email has a token, only
+-- its hash is kept like with sessions.
+CREATE TABLE invites (
+    id BIGSERIAL PRIMARY KEY,
+    token_hash TEXT NOT NULL UNIQUE,
+    team VARCHAR(7) NOT NULL REFERENCES teams (id) ON DELETE CASCADE,
+    -- Only someone logged in with this address can accept it
+    email TEXT NOT NULL,
+    invited_by TEXT NOT NULL REFERENCES users (id),
+    created TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires TIMESTAMPTZ NOT NULL
+);
+-- Inviting someone again replaces their old invite
+CREATE UNIQUE INDEX invites_team_email ON invites (team, lower(email));
@@ -0,0 +1,28 @@
+-- People can stop getting emails about their team
+ALTER TABLE users ADD COLUMN email_opt_out BOOLEAN NOT NULL DEFAULT false;
+
+-- Emails waiting to be sent, or which have been. They are queued in the same transaction as
+-- whatever they are about, and sent in the background so a mail server being down doesn't stop
+-- anything else working.
+CREATE TABLE email_queue (
+    id BIGSERIAL PRIMARY KEY,
+    "user" TEXT REFERENCES users (id) ON DELETE CASCADE,
+    address TEXT NOT NULL,
+    -- Which template to use and what to fill it in with
+    email JSONB NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    -- NULL once it has been sent, or when it has failed too many times to try again
+    next_attempt TIMESTAMPTZ DEFAULT now(),
+    sent TIMESTAMPTZ,
+    created TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX email_queue_next_attempt_idx ON email_queue (next_attempt) WHERE next_attempt IS NOT NULL;
+
+-- Which deadlines teams have been reminded about. The deadline's time is kept so moving it, e.g.
+-- by giving the team an extension, gets them a new reminder.
+CREATE TABLE deadline_reminders (
+    team VARCHAR(7) NOT NULL REFERENCES teams (id) ON DELETE CASCADE,
+    deadline TEXT NOT NULL,
+    "at" TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (team, deadline, "at")
+);
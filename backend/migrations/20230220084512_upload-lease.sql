@@ -0,0 +1,5 @@
+-- Which chunk upload currently holds each upload, instead of keeping its row locked while the
+-- chunk is sent. Taking the lease bumps the number, so a writer whose lease ran out can't update it.
+ALTER TABLE film_uploads
+    ADD COLUMN lease BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN leased_until TIMESTAMPTZ;
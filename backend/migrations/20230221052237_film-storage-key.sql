@@ -0,0 +1,4 @@
+-- Where each film is kept in storage. A new film is stored under a new key before it replaces the
+-- old one, so the row never points at a film with a different size or checksum. Films stored
+-- before this was added are kept under their team's id.
+ALTER TABLE films ADD COLUMN storage_key TEXT;
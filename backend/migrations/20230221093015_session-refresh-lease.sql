@@ -0,0 +1,6 @@
+-- Which request is refreshing each session's tokens, instead of keeping its row locked while the
+-- provider is asked. Taking the lease bumps the number, so a request whose lease ran out can't
+-- store its tokens over newer ones.
+ALTER TABLE sessions
+    ADD COLUMN refresh_lease BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN refreshing_until TIMESTAMPTZ;
@@ -0,0 +1,3 @@
+-- The code someone asked to join with, so a single use code is only used up once they're let in
+ALTER TABLE join_requests
+ADD COLUMN code TEXT;
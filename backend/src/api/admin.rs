@@ -1,18 +1,116 @@
 use actix_web::{
-    get,
+    get, post,
     web::{self},
     HttpResponse, Scope,
 };
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tracing::warn;
 
-use crate::{auth::AdminUser, db::Db, Error};
+use crate::{
+    auth::{perm, Authorized},
+    db::{Category, Db, DeadlineExtension, Role, Schedule},
+    films::FilmStore,
+    Error,
+};
 
 pub fn service() -> Scope {
-    Scope::new("/admin").service(get_teams).service(hello_world)
+    Scope::new("/admin")
+        .service(get_teams)
+        .service(hello_world)
+        .service(get_schedule)
+        .service(set_schedule)
+        .service(rename_team)
+        .service(delete_team)
+        .service(merge_team)
+        .service(move_user)
+        .service(restore_team)
+        .service(get_membership_changes)
+        .service(send_judging_results)
+        .service(get_unsent_emails)
+        .service(retry_email)
+        .service(get_extensions)
+        .service(set_extension)
+        .service(remove_extension)
+        .service(set_category)
+        .service(remove_category)
+        .service(get_sessions)
+        .service(revoke_session)
+        .service(revoke_user_sessions)
+        .service(get_users)
+        .service(get_user)
+        .service(disable_user)
+        .service(enable_user)
+        .service(get_audit_log)
+        .service(get_roles)
+        .service(set_role)
+        .service(remove_role)
+        .service(get_user_roles)
+        .service(add_user_role)
+        .service(remove_user_role)
+}
+
+#[derive(Deserialize)]
+struct SessionsParams {
+    user: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UsersParams {
+    search: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AuditParams {
+    subject: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TeamsParams {
+    category: Option<String>,
+    /// List the archived teams instead
+    #[serde(default)]
+    archived: bool,
+}
+
+#[derive(Deserialize)]
+struct RenameParams {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct MergeParams {
+    into: String,
+}
+
+#[derive(Deserialize)]
+struct MoveParams {
+    team: String,
+}
+
+#[derive(Deserialize)]
+struct ResultsParams {
+    /// How they did, which goes in the email as it is
+    result: String,
+}
+
+#[derive(Deserialize)]
+struct ExtensionParams {
+    submission_deadline: Option<DateTime<Utc>>,
+    edits_lock: Option<DateTime<Utc>>,
 }
 
 #[get("/teams")]
-async fn get_teams(db: web::Data<Db>, _: AdminUser) -> Result<HttpResponse, Error> {
-    db.get_teams().await.map(|x| HttpResponse::Ok().json(x))
+async fn get_teams(
+    db: web::Data<Db>,
+    _: Authorized<perm::ViewTeams>,
+    params: web::Query<TeamsParams>,
+) -> Result<HttpResponse, Error> {
+    let TeamsParams { category, archived } = params.into_inner();
+
+    db.get_teams(category, archived)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
 }
 
 #[get("/")]
@@ -20,6 +118,432 @@ async fn hello_world() -> HttpResponse {
     HttpResponse::Ok().body("Hello world!")
 }
 
+#[post("/teams/{id}/rename")]
+async fn rename_team(
+    db: web::Data<Db>,
+    admin: Authorized<perm::EditTeams>,
+    id: web::Path<String>,
+    params: web::Json<RenameParams>,
+) -> Result<HttpResponse, Error> {
+    db.rename_team(&admin.0.id, id.into_inner(), params.into_inner().name)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// Deletes a team, its film and everyone's membership of it
+#[post("/teams/{id}/delete")]
+async fn delete_team(
+    db: web::Data<Db>,
+    store: web::Data<FilmStore>,
+    admin: Authorized<perm::EditTeams>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let id = id.into_inner();
+    let (team, film) = db.delete_team(&admin.0.id, id.clone()).await?;
+
+    // The team is already gone, a film left behind only takes up space
+    if store.remove(&id, film.as_deref()).await.is_err() {
+        warn!("Couldn't remove the film of deleted team {id}");
+    }
+
+    Ok(HttpResponse::Ok().json(team))
+}
+
+/// Moves everyone into another team, deleting this one
+#[post("/teams/{id}/merge")]
+async fn merge_team(
+    db: web::Data<Db>,
+    admin: Authorized<perm::EditTeams>,
+    id: web::Path<String>,
+    params: web::Json<MergeParams>,
+) -> Result<HttpResponse, Error> {
+    db.merge_teams(&admin.0.id, id.into_inner(), params.into_inner().into)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/users/{id}/team")]
+async fn move_user(
+    db: web::Data<Db>,
+    admin: Authorized<perm::EditTeams>,
+    id: web::Path<String>,
+    params: web::Json<MoveParams>,
+) -> Result<HttpResponse, Error> {
+    db.move_user(&admin.0.id, id.into_inner(), params.into_inner().team)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// Brings back a team which was archived for being empty
+#[post("/teams/{id}/restore")]
+async fn restore_team(
+    db: web::Data<Db>,
+    admin: Authorized<perm::EditTeams>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    db.restore_team(&admin.0.id, id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// Everyone who has joined or left the team
+#[get("/teams/{id}/history")]
+async fn get_membership_changes(
+    db: web::Data<Db>,
+    _: Authorized<perm::ViewTeams>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    db.get_membership_changes(id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// Emails everyone in the team how they did
+#[post("/teams/{id}/results")]
+async fn send_judging_results(
+    db: web::Data<Db>,
+    admin: Authorized<perm::ManageFestival>,
+    id: web::Path<String>,
+    params: web::Json<ResultsParams>,
+) -> Result<HttpResponse, Error> {
+    db.send_judging_results(&admin.0.id, id.into_inner(), params.into_inner().result)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// Emails still waiting to be sent, or which couldn't be
+#[get("/emails")]
+async fn get_unsent_emails(
+    db: web::Data<Db>,
+    _: Authorized<perm::ManageFestival>,
+) -> Result<HttpResponse, Error> {
+    db.get_unsent_emails()
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/emails/{id}/retry")]
+async fn retry_email(
+    db: web::Data<Db>,
+    admin: Authorized<perm::ManageFestival>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, Error> {
+    db.retry_email(&admin.0.id, id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[get("/schedule")]
+async fn get_schedule(
+    db: web::Data<Db>,
+    _: Authorized<perm::ManageFestival>,
+) -> Result<HttpResponse, Error> {
+    db.get_schedule().await.map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/schedule")]
+async fn set_schedule(
+    db: web::Data<Db>,
+    _: Authorized<perm::ManageFestival>,
+    schedule: web::Json<Schedule>,
+) -> Result<HttpResponse, Error> {
+    let schedule = schedule.into_inner();
+
+    if let (Some(opens), Some(closes)) = (schedule.registration_opens, schedule.registration_closes)
+    {
+        if opens >= closes {
+            return Err(Error::InvalidSchedule(
+                "registration has to open before it closes".into(),
+            ));
+        }
+    }
+    if let (Some(deadline), Some(lock)) = (schedule.submission_deadline, schedule.edits_lock) {
+        if lock < deadline {
+            return Err(Error::InvalidSchedule(
+                "film details can't be locked before the submission deadline".into(),
+            ));
+        }
+    }
+    if schedule.max_team_size.map(|x| x < 1).unwrap_or(false) {
+        return Err(Error::InvalidSchedule(
+            "teams have to be allowed at least one member".into(),
+        ));
+    }
+
+    db.set_schedule(schedule)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[get("/extensions")]
+async fn get_extensions(
+    db: web::Data<Db>,
+    _: Authorized<perm::EditTeams>,
+) -> Result<HttpResponse, Error> {
+    db.get_deadline_extensions()
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/teams/{id}/extension")]
+async fn set_extension(
+    db: web::Data<Db>,
+    _: Authorized<perm::EditTeams>,
+    id: web::Path<String>,
+    params: web::Json<ExtensionParams>,
+) -> Result<HttpResponse, Error> {
+    let ExtensionParams {
+        submission_deadline,
+        edits_lock,
+    } = params.into_inner();
+
+    db.set_deadline_extension(DeadlineExtension {
+        team: id.into_inner(),
+        submission_deadline,
+        edits_lock,
+    })
+    .await
+    .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/teams/{id}/extension/remove")]
+async fn remove_extension(
+    db: web::Data<Db>,
+    _: Authorized<perm::EditTeams>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    db.remove_deadline_extension(id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/categories")]
+async fn set_category(
+    db: web::Data<Db>,
+    _: Authorized<perm::ManageFestival>,
+    category: web::Json<Category>,
+) -> Result<HttpResponse, Error> {
+    let mut category = category.into_inner();
+    category.name = category.name.trim().to_owned();
+    category.email_domain = category
+        .email_domain
+        .map(|x| x.trim().trim_start_matches('@').to_lowercase())
+        .filter(|x| !x.is_empty());
+
+    if category.id.is_empty()
+        || !category
+            .id
+            .chars()
+            .all(|x| x.is_ascii_lowercase() || x.is_ascii_digit() || x == '-')
+    {
+        return Err(Error::InvalidCategory(
+            "ids can only have lowercase letters, numbers and dashes".into(),
+        ));
+    }
+    if category.name.is_empty() {
+        return Err(Error::InvalidCategory("categories need a name".into()));
+    }
+    if category.max_team_size.map(|x| x < 1).unwrap_or(false) {
+        return Err(Error::InvalidCategory(
+            "teams have to be allowed at least one member".into(),
+        ));
+    }
+
+    db.set_category(category)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/categories/{id}/remove")]
+async fn remove_category(
+    db: web::Data<Db>,
+    _: Authorized<perm::ManageFestival>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    db.remove_category(id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[get("/sessions")]
+async fn get_sessions(
+    db: web::Data<Db>,
+    _: Authorized<perm::ManageUsers>,
+    params: web::Query<SessionsParams>,
+) -> Result<HttpResponse, Error> {
+    db.get_sessions(params.into_inner().user)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/sessions/{id}/revoke")]
+async fn revoke_session(
+    db: web::Data<Db>,
+    admin: Authorized<perm::ManageUsers>,
+    id: web::Path<i64>,
+) -> Result<HttpResponse, Error> {
+    db.revoke_session(&admin.0.id, id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// Logs someone out everywhere
+#[post("/users/{id}/sessions/revoke")]
+async fn revoke_user_sessions(
+    db: web::Data<Db>,
+    admin: Authorized<perm::ManageUsers>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    db.revoke_user_sessions(&admin.0.id, id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[get("/users")]
+async fn get_users(
+    db: web::Data<Db>,
+    _: Authorized<perm::ManageUsers>,
+    params: web::Query<UsersParams>,
+) -> Result<HttpResponse, Error> {
+    let search = params.into_inner().search.filter(|x| !x.trim().is_empty());
+
+    db.search_users(search.map(|x| x.trim().to_owned()))
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[get("/users/{id}")]
+async fn get_user(
+    db: web::Data<Db>,
+    _: Authorized<perm::ManageUsers>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    db.get_user_details(id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// Stops someone logging in or using the API
+#[post("/users/{id}/disable")]
+async fn disable_user(
+    db: web::Data<Db>,
+    admin: Authorized<perm::ManageUsers>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    db.set_user_disabled(&admin.0.id, id.into_inner(), true)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/users/{id}/enable")]
+async fn enable_user(
+    db: web::Data<Db>,
+    admin: Authorized<perm::ManageUsers>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    db.set_user_disabled(&admin.0.id, id.into_inner(), false)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// Who changed which users and roles
+#[get("/audit")]
+async fn get_audit_log(
+    db: web::Data<Db>,
+    _: Authorized<perm::ManageUsers>,
+    params: web::Query<AuditParams>,
+) -> Result<HttpResponse, Error> {
+    db.get_audit_log(params.into_inner().subject)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[get("/roles")]
+async fn get_roles(
+    db: web::Data<Db>,
+    _: Authorized<perm::ManageUsers>,
+) -> Result<HttpResponse, Error> {
+    db.get_roles().await.map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/roles")]
+async fn set_role(
+    db: web::Data<Db>,
+    admin: Authorized<perm::ManageUsers>,
+    role: web::Json<Role>,
+) -> Result<HttpResponse, Error> {
+    let mut role = role.into_inner();
+    role.name = role.name.trim().to_owned();
+    role.permissions.sort_by_key(|x| *x as u8);
+    role.permissions.dedup();
+
+    if role.id.is_empty()
+        || !role
+            .id
+            .chars()
+            .all(|x| x.is_ascii_lowercase() || x.is_ascii_digit() || x == '-')
+    {
+        return Err(Error::InvalidRole(
+            "ids can only have lowercase letters, numbers and dashes".into(),
+        ));
+    }
+    if role.name.is_empty() {
+        return Err(Error::InvalidRole("roles need a name".into()));
+    }
+
+    db.set_role(&admin.0.id, role)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/roles/{id}/remove")]
+async fn remove_role(
+    db: web::Data<Db>,
+    admin: Authorized<perm::ManageUsers>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    db.remove_role(&admin.0.id, id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[get("/users/{id}/roles")]
+async fn get_user_roles(
+    db: web::Data<Db>,
+    _: Authorized<perm::ManageUsers>,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    db.get_user_roles(id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/users/{id}/roles/{role}")]
+async fn add_user_role(
+    db: web::Data<Db>,
+    admin: Authorized<perm::ManageUsers>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (id, role) = path.into_inner();
+
+    db.add_user_role(&admin.0.id, id, role)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/users/{id}/roles/{role}/remove")]
+async fn remove_user_role(
+    db: web::Data<Db>,
+    admin: Authorized<perm::ManageUsers>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (id, role) = path.into_inner();
+
+    db.remove_user_role(&admin.0.id, id, role)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
 // #[post("/team/:id/project")]
 // async fn get_team_info() -> Result<HttpResponse, Error> {
 //     Ok(HttpResponse::Ok().finish())
@@ -1,25 +1,38 @@
-use crate::data::{Auth0Config, UrlConfig};
+use crate::{
+    auth::{parse_jwt, User},
+    data::{random_token, CookiePolicy, LoginState, OidcConfig, UrlConfig, LOGIN_STATE_EXPIRY},
+    db::Db,
+    oidc::Provider,
+    Error,
+};
 use actix_web::{
-    cookie::{time::Duration, CookieBuilder, SameSite},
+    cookie::{time::Duration, Cookie, CookieBuilder, CookieJar, Key, SameSite},
     get,
     http::header,
     web::{Data, Query},
-    HttpResponse, Responder, Scope,
+    HttpRequest, HttpResponse, Scope,
 };
+use chrono::Utc;
+use reqwest::Url;
 use serde::Deserialize;
-use std::collections::HashMap;
-use tracing::debug;
+use tracing::{error, warn};
+
+const LOGIN_STATE_COOKIE: &str = "login_state";
+/// Cookie holding a token issued by development login
+pub(super) const ACCESS_TOKEN_COOKIE: &str = "access_token";
+/// How long someone stays logged in for, as long as their tokens can be refreshed, in seconds
+const SESSION_LIFETIME: i64 = 30 * 24 * 60 * 60;
 
-#[derive(Deserialize, Clone)]
-pub struct OAuthTokenResponse {
-    id_token: String,
-    expires_in: i32,
+#[derive(Deserialize)]
+pub(super) struct LoginParams {
+    /// Token from a team invite's link
+    pub invite: Option<String>,
 }
 
 #[derive(Deserialize, Clone, Debug)]
-pub struct Auth0Url {
+pub struct CallbackParams {
     code: String,
-    // state: String,
+    state: String,
 }
 
 pub fn auth() -> actix_web::Scope {
@@ -29,134 +42,283 @@ pub fn auth() -> actix_web::Scope {
         .service(logout)
 }
 
+/// Encrypts the login state into a cookie which only gets sent back to `/auth`
+fn login_state_cookie(
+    key: &Key,
+    cookies: &CookiePolicy,
+    login_state: &LoginState,
+) -> Result<Cookie<'static>, Error> {
+    let value = serde_json::to_string(login_state).map_err(|ex| {
+        error!("Couldn't serialize login state {ex}");
+        Error::InternalError
+    })?;
+
+    let mut jar = CookieJar::new();
+    jar.private_mut(key).add(
+        CookieBuilder::new(LOGIN_STATE_COOKIE, value)
+            .max_age(Duration::seconds(LOGIN_STATE_EXPIRY))
+            .http_only(true)
+            .path("/auth")
+            // Lax still sends it on the redirect back from the provider
+            .same_site(SameSite::Lax)
+            .secure(cookies.secure)
+            .finish(),
+    );
+
+    jar.get(LOGIN_STATE_COOKIE)
+        .cloned()
+        .ok_or(Error::InternalError)
+}
+
+/// Where to send someone who has just logged in, after putting them in the team they were invited
+/// to. Not being able to join it shouldn't stop them logging in, so the frontend is just told why.
+pub(super) async fn after_login(
+    db: &Db,
+    frontend: &str,
+    user: User,
+    invite: Option<String>,
+) -> String {
+    match invite {
+        Some(invite) => match db.accept_invite(user, &invite).await {
+            Ok(()) => format!("{frontend}/participate"),
+            Err(ex) => {
+                warn!("Couldn't accept invite {ex}");
+                format!("{frontend}/participate?invite_error={}", ex.as_number())
+            }
+        },
+        None => format!("{frontend}/participate"),
+    }
+}
+
+/// Decrypts the login state cookie, if it was sent and hasn't been tampered with
+fn read_login_state(req: &HttpRequest, key: &Key) -> Option<LoginState> {
+    let mut jar = CookieJar::new();
+    jar.add_original(req.cookie(LOGIN_STATE_COOKIE)?);
+
+    let cookie = jar.private(key).get(LOGIN_STATE_COOKIE)?;
+    serde_json::from_str(cookie.value()).ok()
+}
+
 #[get("/login")]
 async fn login(
-    // states: Data<Mutex<States>>,
-    auth0: Data<Auth0Config>,
+    params: Query<LoginParams>,
+    oidc: Data<OidcConfig>,
+    provider: Data<Provider>,
     urls: Data<UrlConfig>,
-) -> HttpResponse {
+    cookies: Data<CookiePolicy>,
+    key: Data<Key>,
+) -> Result<HttpResponse, Error> {
     let UrlConfig { backend, .. } = urls.as_ref();
-    let Auth0Config {
-        client_id, domain, ..
-    } = auth0.as_ref();
-
-    // let random_string = states.lock().await.add();
+    let OidcConfig {
+        client_id, scopes, ..
+    } = oidc.as_ref();
+    let metadata = provider.metadata().await?;
 
+    let login_state = LoginState {
+        invite: params.into_inner().invite,
+        ..LoginState::generate()
+    };
     let callback_url = format!("{backend}/auth/callback?");
-    let callback_url = urlencoding::encode(&callback_url).to_string();
-
-    HttpResponse::Found()
-        .append_header((
-            header::LOCATION,
-            format!(
-                /*  "https://{domain}/authorize?\
-                client_id={client_id}&\
-                redirect_uri={callback_url}&\
-                response_type=code&\
-                state={random_string}&\
-                scope=openid profile email user_id"*/
-                "https://{domain}/authorize?\
-client_id={client_id}&\
-redirect_uri={callback_url}&\
-response_type=code&\
-scope=openid profile email user_id"
-            ),
-        ))
-        .finish()
+
+    let location = Url::parse_with_params(
+        &metadata.authorization_endpoint,
+        [
+            ("client_id", client_id.as_str()),
+            ("redirect_uri", callback_url.as_str()),
+            ("response_type", "code"),
+            ("state", login_state.state.as_str()),
+            ("code_challenge", login_state.challenge().as_str()),
+            ("code_challenge_method", "S256"),
+            ("scope", scopes.as_str()),
+        ],
+    )
+    .map_err(|ex| {
+        error!("Invalid authorization endpoint {ex}");
+        Error::AuthUnavailable
+    })?;
+
+    Ok(HttpResponse::Found()
+        .cookie(login_state_cookie(&key, &cookies, &login_state)?)
+        .append_header((header::LOCATION, location.to_string()))
+        .finish())
 }
 
 #[get("/callback")]
+#[allow(clippy::too_many_arguments)]
 async fn login_callback(
-    req: Query<Auth0Url>,
-    // states: Data<Mutex<States>>,
-    config: Data<Auth0Config>,
+    http_req: HttpRequest,
+    req: Query<CallbackParams>,
+    db: Data<Db>,
+    config: Data<OidcConfig>,
+    provider: Data<Provider>,
     url_config: Data<UrlConfig>,
-) -> impl Responder {
+    cookies: Data<CookiePolicy>,
+    key: Data<Key>,
+) -> Result<HttpResponse, Error> {
     let UrlConfig { frontend, backend } = url_config.as_ref();
+    let CallbackParams { code, state } = req.0;
 
-    let Auth0Config {
-        client_id,
-        domain,
-        client_secret,
-        ..
-    } = config.get_ref();
-
-    let Auth0Url { code } = req.0;
+    let login_state = read_login_state(&http_req, &key)
+        .filter(|x| !x.is_expired() && x.state == state)
+        .ok_or_else(|| {
+            warn!("Login callback with a missing, expired or mismatched state");
+            Error::InvalidLoginState
+        })?;
 
-    // if states.lock().await.check(&state) {
-    // if true {
     let callback_url = format!("{backend}/auth/callback?");
+    let tokens = provider
+        .request_tokens(&[
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("redirect_uri", callback_url.as_str()),
+            ("code_verifier", login_state.verifier.as_str()),
+        ])
+        .await?;
 
-    let params = HashMap::from([
-        ("grant_type", "authorization_code"),
-        ("client_id", client_id),
-        ("client_secret", client_secret),
-        ("code", code.as_str()),
-        ("redirect_uri", callback_url.as_str()),
-    ]);
-
-    let request = reqwest::Client::new()
-        .post(format!("https://{domain}/oauth/token"))
-        .form(&params)
-        .send()
-        .await;
-
-    if let Ok(res) = request {
-        if res.status() != 200 {
-            debug!("Failed to get token: {}", res.text().await.unwrap());
-            return HttpResponse::InternalServerError().body("Couldn't get token");
-        }
+    let user = parse_jwt(&provider, &tokens.id_token, &config).await?;
+    // Sessions belong to a user, so they need to exist first
+    db.get_user(user.clone()).await?;
 
-        let OAuthTokenResponse {
-            id_token,
-            expires_in,
-        } = res.json().await.unwrap();
-
-        HttpResponse::TemporaryRedirect()
-            .append_header((header::LOCATION, format!("{frontend}/participate")))
-            .append_header((
-                header::SET_COOKIE,
-                CookieBuilder::new("access_token", id_token)
-                    .max_age(Duration::seconds(expires_in as i64))
-                    .http_only(true)
-                    .path("/")
-                    .same_site(SameSite::None)
-                    .secure(true)
-                    .domain(
-                        frontend
-                            .strip_prefix("https://")
-                            .or_else(|| {
-                                frontend
-                                    .strip_prefix("http://")
-                                    .unwrap()
-                                    .strip_suffix(":5173")
-                            })
-                            .unwrap(),
-                    )
-                    .finish()
-                    .to_string(),
-            ))
-            .finish()
-    } else {
-        eprintln!("{}", request.unwrap_err());
-        HttpResponse::InternalServerError().body("Could not get new token")
-    }
-    // } else {
-    //     HttpResponse::BadRequest().body("Invalid state")
-    // }
+    let session = random_token();
+    db.create_session(
+        user.id.clone(),
+        &session,
+        tokens.id_token,
+        Utc::now() + chrono::Duration::seconds(tokens.expires_in),
+        tokens.refresh_token,
+        Utc::now() + chrono::Duration::seconds(SESSION_LIFETIME),
+    )
+    .await?;
+
+    let mut removal = Cookie::named(LOGIN_STATE_COOKIE);
+    removal.set_path("/auth");
+    removal.make_removal();
+
+    let location = after_login(&db, frontend, user, login_state.invite).await;
+
+    Ok(HttpResponse::TemporaryRedirect()
+        .append_header((header::LOCATION, location))
+        .cookie(removal)
+        .cookie(cookies.build(cookies.name.clone(), session, SESSION_LIFETIME))
+        .finish())
 }
+
+/// Ends the session, then logs out of the provider too so the next login on a shared computer
+/// doesn't go straight back in as the same person
 #[get("/logout")]
-async fn logout(public_config: Data<UrlConfig>) -> HttpResponse {
-    HttpResponse::TemporaryRedirect()
-        .append_header((
-            header::SET_COOKIE,
-            CookieBuilder::new("access_token", "")
-                .max_age(Duration::seconds(-1))
-                .path("/")
-                .http_only(true)
-                .finish()
-                .to_string(),
-        ))
-        .append_header((header::LOCATION, format!("{}", public_config.frontend)))
-        .finish()
+pub(super) async fn logout(
+    req: HttpRequest,
+    db: Data<Db>,
+    provider: Option<Data<Provider>>,
+    public_config: Data<UrlConfig>,
+    cookies: Data<CookiePolicy>,
+) -> Result<HttpResponse, Error> {
+    let UrlConfig { frontend, .. } = public_config.as_ref();
+
+    let id_token = match req.cookie(&cookies.name) {
+        Some(session) => db.end_session(session.value()).await?,
+        None => None,
+    };
+
+    let location = match provider {
+        Some(provider) => provider
+            .logout_url(frontend, id_token.as_deref())
+            .await
+            .map(|x| x.to_string()),
+        None => None,
+    }
+    .unwrap_or_else(|| frontend.clone());
+
+    Ok(HttpResponse::TemporaryRedirect()
+        .cookie(cookies.build(cookies.name.clone(), String::new(), 0))
+        .cookie(cookies.build(ACCESS_TOKEN_COOKIE.into(), String::new(), 0))
+        .append_header((header::LOCATION, location))
+        .finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{CookieConfig, SameSitePolicy};
+    use actix_web::test::TestRequest;
+
+    fn cookies() -> CookiePolicy {
+        CookieConfig {
+            name: "session".into(),
+            domain: None,
+            same_site: SameSitePolicy::Lax,
+            secure: None,
+            path: "/".into(),
+        }
+        .validate(&UrlConfig {
+            backend: "https://example.com".into(),
+            frontend: "https://example.com".into(),
+        })
+        .unwrap()
+    }
+
+    fn read_cookie(cookie: Cookie<'static>, key: &Key) -> Option<LoginState> {
+        read_login_state(
+            &TestRequest::default().cookie(cookie).to_http_request(),
+            key,
+        )
+    }
+
+    #[test]
+    fn login_state_round_trip() {
+        let key = Key::generate();
+        let state = LoginState {
+            invite: Some(random_token()),
+            ..LoginState::generate()
+        };
+        let cookie = login_state_cookie(&key, &cookies(), &state).unwrap();
+
+        // Nothing in it can be read without the key
+        assert!(!cookie.value().contains(&state.state));
+        assert!(!cookie.value().contains(&state.verifier));
+        assert_eq!(cookie.path(), Some("/auth"));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.secure(), Some(true));
+
+        let read = read_cookie(cookie, &key).unwrap();
+        assert_eq!(read.state, state.state);
+        assert_eq!(read.verifier, state.verifier);
+        assert_eq!(read.expires, state.expires);
+        assert_eq!(read.invite, state.invite);
+    }
+
+    #[test]
+    fn tampered_login_state() {
+        let key = Key::generate();
+        let mut cookie = login_state_cookie(&key, &cookies(), &LoginState::generate()).unwrap();
+
+        let mut value = cookie.value().as_bytes().to_vec();
+        value[10] = if value[10] == b'A' { b'B' } else { b'A' };
+        cookie.set_value(String::from_utf8(value).unwrap());
+
+        assert!(read_cookie(cookie, &key).is_none());
+    }
+
+    #[test]
+    fn login_state_from_another_key() {
+        let cookie =
+            login_state_cookie(&Key::generate(), &cookies(), &LoginState::generate()).unwrap();
+
+        assert!(read_cookie(cookie, &Key::generate()).is_none());
+    }
+
+    #[test]
+    fn missing_login_state() {
+        let req = TestRequest::default().to_http_request();
+
+        assert!(read_login_state(&req, &Key::generate()).is_none());
+    }
+
+    #[test]
+    fn plain_login_state() {
+        // Someone making up their own state, without encrypting it
+        let value = serde_json::to_string(&LoginState::generate()).unwrap();
+
+        assert!(read_cookie(Cookie::new(LOGIN_STATE_COOKIE, value), &Key::generate()).is_none());
+    }
 }
@@ -0,0 +1,140 @@
+use crate::{
+    api::auth::{after_login, logout, LoginParams, ACCESS_TOKEN_COOKIE},
+    auth::User,
+    data::{CookiePolicy, UrlConfig},
+    db::Db,
+    dev_auth::{DevIssuer, DEV_TOKEN_EXPIRY},
+    Error,
+};
+use actix_web::{
+    get,
+    http::header::{self, ContentType},
+    post,
+    web::{Data, Json, Query},
+    HttpResponse, Scope,
+};
+use serde::{Deserialize, Serialize};
+
+/// Picks who to log in as, in place of the provider's login page
+const LOGIN_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>Development login</title></head>
+<body>
+<h1>Development login</h1>
+<form action="dev/login" method="get">
+<p><label>Id <input name="id" required></label></p>
+<p><label>Name <input name="name"></label></p>
+<p><label>Email <input name="email" type="email"></label></p>
+<p><label><input name="admin" type="checkbox" value="true"> Organiser</label></p>
+<!-- invite -->
+<p><button>Log in</button></p>
+</form>
+</body>
+</html>
+"#;
+
+/// Replaces the OIDC login when development login is enabled
+pub fn dev_auth() -> Scope {
+    Scope::new("/auth")
+        .service(login)
+        .service(dev_login)
+        .service(dev_token)
+        .service(logout)
+}
+
+#[derive(Deserialize)]
+struct DevUserParams {
+    id: String,
+    name: Option<String>,
+    email: Option<String>,
+    #[serde(default)]
+    admin: bool,
+    /// Token from a team invite's link
+    invite: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DevTokenResponse {
+    token: String,
+    expires_in: i64,
+}
+
+/// Makes the user up, saving whether they are an organiser
+async fn dev_user(db: &Db, params: DevUserParams) -> Result<User, Error> {
+    let DevUserParams {
+        id,
+        name,
+        email,
+        admin,
+        ..
+    } = params;
+
+    let user = User {
+        name: name.filter(|x| !x.is_empty()).unwrap_or_else(|| id.clone()),
+        email: email
+            .filter(|x| !x.is_empty())
+            .unwrap_or_else(|| format!("{id}@example.com")),
+        // Whoever logs in as a made up user can pick any address anyway
+        email_verified: true,
+        id,
+    };
+
+    let roles: &[&str] = if admin { &["organiser"] } else { &[] };
+    db.set_user_roles(user.clone(), roles).await?;
+
+    Ok(user)
+}
+
+#[get("/login")]
+async fn login(params: Query<LoginParams>) -> HttpResponse {
+    // Tokens are URL safe base64, anything else could break out of the attribute
+    let invite = params
+        .into_inner()
+        .invite
+        .filter(|x| {
+            x.chars()
+                .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+        })
+        .map(|x| format!(r#"<input name="invite" type="hidden" value="{x}">"#))
+        .unwrap_or_default();
+
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(LOGIN_PAGE.replace("<!-- invite -->", &invite))
+}
+
+#[get("/dev/login")]
+async fn dev_login(
+    db: Data<Db>,
+    issuer: Data<DevIssuer>,
+    url_config: Data<UrlConfig>,
+    cookies: Data<CookiePolicy>,
+    params: Query<DevUserParams>,
+) -> Result<HttpResponse, Error> {
+    let UrlConfig { frontend, .. } = url_config.as_ref();
+    let params = params.into_inner();
+    let invite = params.invite.clone();
+    let user = dev_user(&db, params).await?;
+    let token = issuer.issue(user.clone())?;
+    let location = after_login(&db, frontend, user, invite).await;
+
+    Ok(HttpResponse::TemporaryRedirect()
+        .append_header((header::LOCATION, location))
+        .cookie(cookies.build(ACCESS_TOKEN_COOKIE.into(), token, DEV_TOKEN_EXPIRY))
+        .finish())
+}
+
+/// Issues a token to use in the `Authorization` header, for tests
+#[post("/dev/token")]
+async fn dev_token(
+    db: Data<Db>,
+    issuer: Data<DevIssuer>,
+    params: Json<DevUserParams>,
+) -> Result<HttpResponse, Error> {
+    let user = dev_user(&db, params.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(DevTokenResponse {
+        token: issuer.issue(user)?,
+        expires_in: DEV_TOKEN_EXPIRY,
+    }))
+}
@@ -1,18 +1,68 @@
 mod admin;
 pub mod auth;
+pub mod dev_auth;
 mod teams;
 
-use crate::{auth::User, db::Db, Error};
-use actix_web::{get, web, HttpResponse, Scope};
+use crate::{
+    auth::User,
+    db::{self, Db, Permission},
+    Error,
+};
+use actix_web::{get, post, web, HttpResponse, Scope};
+use serde::{Deserialize, Serialize};
 
 pub fn api() -> Scope {
     Scope::new("/api")
         .service(admin::service())
         .service(get_user)
+        .service(set_email_opt_out)
+        .service(get_schedule)
+        .service(get_categories)
         .service(teams::service())
 }
 
+/// The festival's dates, so they can be shown to everyone
+#[get("/schedule")]
+async fn get_schedule(db: web::Data<Db>) -> Result<HttpResponse, Error> {
+    db.get_schedule().await.map(|x| HttpResponse::Ok().json(x))
+}
+
+#[get("/categories")]
+async fn get_categories(db: web::Data<Db>) -> Result<HttpResponse, Error> {
+    db.get_categories()
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// The logged in user, with what their roles let them do so the frontend can show it
+#[derive(Serialize)]
+struct CurrentUser {
+    #[serde(flatten)]
+    user: db::User,
+    permissions: Vec<Permission>,
+}
+
 #[get("/user")]
 async fn get_user(db: web::Data<Db>, user: User) -> Result<HttpResponse, Error> {
-    db.get_user(user).await.map(|x| HttpResponse::Ok().json(x))
+    let permissions = db.get_permissions(&user).await?;
+    let user = db.get_user(user).await?;
+
+    Ok(HttpResponse::Ok().json(CurrentUser { user, permissions }))
+}
+
+#[derive(Deserialize)]
+struct EmailParams {
+    opt_out: bool,
+}
+
+/// Stops or starts emails about the user's team
+#[post("/user/email")]
+async fn set_email_opt_out(
+    db: web::Data<Db>,
+    user: User,
+    params: web::Json<EmailParams>,
+) -> Result<HttpResponse, Error> {
+    db.set_email_opt_out(user, params.into_inner().opt_out)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
 }
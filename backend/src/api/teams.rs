@@ -1,29 +1,125 @@
 use actix_web::{
-    get, post,
+    get,
+    http::header::{
+        self, ContentDisposition, ContentRange, ContentRangeSpec, ContentType, DispositionParam,
+        DispositionType, ETag, EntityTag, IfNoneMatch, IfRange, Range,
+    },
+    post,
     web::{self, Data},
-    HttpResponse, Scope,
+    HttpMessage, HttpRequest, HttpResponse, Scope,
 };
+use chrono::{DateTime, Utc};
+use lettre::Address;
 use serde::Deserialize;
+use tracing::warn;
 
-use crate::{auth::User, db::Db, Error};
+use crate::{
+    auth::User,
+    data::{random_token, FilmConfig},
+    db::{Db, FilmUpload},
+    films::{self, FilmStore},
+    mail::{Email, Mailer},
+    Error,
+};
 
 pub fn service() -> Scope {
     Scope::new("/team")
         .service(get_team)
         .service(join_team)
+        .service(cancel_join_request)
         .service(create_team)
         .service(get_members)
         .service(leave_team)
+        .service(remove_member)
+        .service(transfer_captaincy)
+        .service(get_join_code)
+        .service(regenerate_join_code)
+        .service(revoke_join_code)
+        .service(set_approval_required)
+        .service(get_join_requests)
+        .service(accept_join_request)
+        .service(decline_join_request)
+        .service(get_invites)
+        .service(create_invite)
+        .service(revoke_invite)
+        .service(set_category)
+        .service(get_film)
+        .service(update_film_details)
+        .service(get_film_upload)
+        .service(start_film_upload)
+        .service(upload_film_chunk)
+        .service(download_film)
 }
 
 #[derive(Deserialize)]
 struct TeamParams {
-    id: String,
+    /// Used to be the team's id, which old links still call it
+    #[serde(alias = "id")]
+    code: String,
 }
 
 #[derive(Deserialize)]
 struct CreateTeamParams {
     name: String,
+    category: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CaptainParams {
+    user: String,
+}
+
+#[derive(Deserialize)]
+struct JoinCodeParams {
+    /// The code stops working after this, otherwise it works until it is replaced
+    expires: Option<DateTime<Utc>>,
+    /// The code stops working once someone has joined with it
+    #[serde(default)]
+    single_use: bool,
+}
+
+#[derive(Deserialize)]
+struct InviteParams {
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct ApprovalParams {
+    required: bool,
+}
+
+#[derive(Deserialize)]
+struct CategoryParams {
+    category: String,
+}
+
+#[derive(Deserialize)]
+struct FilmDetailsParams {
+    film_name: String,
+    film_description: String,
+    /// Mark the entry as final
+    #[serde(default)]
+    submit: bool,
+}
+
+#[derive(Deserialize)]
+struct StartUploadParams {
+    file_name: String,
+    size: i64,
+    /// Hex encoded SHA-256 of the whole file
+    checksum: String,
+}
+
+#[derive(Deserialize)]
+struct ChunkParams {
+    offset: i64,
+}
+
+#[derive(Deserialize)]
+struct DownloadParams {
+    /// Show the film in the browser instead of downloading it
+    #[serde(default)]
+    inline: bool,
 }
 
 #[get("/")]
@@ -37,8 +133,20 @@ async fn join_team(
     user: User,
     params: web::Query<TeamParams>,
 ) -> Result<HttpResponse, Error> {
-    let id = params.id.clone();
-    db.join_team(user, id.to_owned())
+    let team = db.join_team(user, params.into_inner().code).await?;
+
+    // They're waiting on the captain, rather than in the team
+    Ok(if team.pending {
+        HttpResponse::Accepted().json(team)
+    } else {
+        HttpResponse::Ok().json(team)
+    })
+}
+
+/// Takes back a request to join a team which needs its captain's approval
+#[post("/join/cancel")]
+async fn cancel_join_request(db: Data<Db>, user: User) -> Result<HttpResponse, Error> {
+    db.cancel_join_request(user)
         .await
         .map(|x| HttpResponse::Ok().json(x))
 }
@@ -49,8 +157,8 @@ async fn create_team(
     user: User,
     params: web::Query<CreateTeamParams>,
 ) -> Result<HttpResponse, Error> {
-    let name = params.name.clone();
-    db.create_team(user, name.to_owned())
+    let CreateTeamParams { name, category } = params.into_inner();
+    db.create_team(user, name, category)
         .await
         .map(|x| HttpResponse::Ok().json(x))
 }
@@ -72,3 +180,462 @@ async fn get_members(
         .await
         .map(|x| HttpResponse::Ok().json(x))
 }
+
+/// Lets the captain take someone out of the team
+#[post("/{id}/members/{user}/remove")]
+async fn remove_member(
+    db: Data<Db>,
+    user: User,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (id, member) = path.into_inner();
+
+    db.remove_member(user, id, member)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// Makes another member the captain
+#[post("/{id}/captain")]
+async fn transfer_captaincy(
+    db: Data<Db>,
+    user: User,
+    id: web::Path<String>,
+    params: web::Json<CaptainParams>,
+) -> Result<HttpResponse, Error> {
+    db.transfer_captaincy(user, id.into_inner(), params.into_inner().user)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// The code people can join the team with, or null if it has been revoked or used up
+#[get("/{id}/code")]
+async fn get_join_code(
+    db: Data<Db>,
+    user: User,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    db.get_join_code(user, id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// Lets the captain replace the join code, so the old one stops working
+#[post("/{id}/code")]
+async fn regenerate_join_code(
+    db: Data<Db>,
+    user: User,
+    id: web::Path<String>,
+    params: web::Json<JoinCodeParams>,
+) -> Result<HttpResponse, Error> {
+    let JoinCodeParams {
+        expires,
+        single_use,
+    } = params.into_inner();
+
+    db.regenerate_join_code(user, id.into_inner(), expires, single_use)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// Stops anyone joining the team until the captain makes a new code
+#[post("/{id}/code/revoke")]
+async fn revoke_join_code(
+    db: Data<Db>,
+    user: User,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    db.revoke_join_code(user, id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// Lets the captain choose whether people joining have to be accepted first
+#[post("/{id}/approval")]
+async fn set_approval_required(
+    db: Data<Db>,
+    user: User,
+    id: web::Path<String>,
+    params: web::Json<ApprovalParams>,
+) -> Result<HttpResponse, Error> {
+    db.set_approval_required(user, id.into_inner(), params.into_inner().required)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// Everyone waiting to be let into the team
+#[get("/{id}/requests")]
+async fn get_join_requests(
+    db: Data<Db>,
+    user: User,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    db.get_join_requests(user, id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/{id}/requests/{user}/accept")]
+async fn accept_join_request(
+    db: Data<Db>,
+    user: User,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (id, requester) = path.into_inner();
+
+    db.accept_join_request(user, id, requester)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/{id}/requests/{user}/decline")]
+async fn decline_join_request(
+    db: Data<Db>,
+    user: User,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (id, requester) = path.into_inner();
+
+    db.decline_join_request(user, id, requester)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// Invites which haven't been accepted yet
+#[get("/{id}/invites")]
+async fn get_invites(
+    db: Data<Db>,
+    user: User,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    db.get_invites(user, id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+/// Lets the captain email someone a link which puts them in the team once they log in
+#[post("/{id}/invites")]
+async fn create_invite(
+    db: Data<Db>,
+    mailer: Data<Mailer>,
+    user: User,
+    id: web::Path<String>,
+    params: web::Json<InviteParams>,
+) -> Result<HttpResponse, Error> {
+    let email = params.into_inner().email.trim().to_owned();
+    if email.parse::<Address>().is_err() {
+        return Err(Error::InvalidEmail(email));
+    }
+
+    let inviter = user.name.clone();
+    let token = random_token();
+    let invite = db
+        .create_invite(user, id.into_inner(), email, &token)
+        .await?;
+
+    mailer
+        .send(
+            &invite.email,
+            &Email::Invite {
+                inviter,
+                team_name: invite.team_name.clone(),
+                token,
+                expires: invite.expires,
+            },
+        )
+        .await?;
+
+    Ok(HttpResponse::Ok().json(invite))
+}
+
+#[post("/{id}/invites/{invite}/revoke")]
+async fn revoke_invite(
+    db: Data<Db>,
+    user: User,
+    path: web::Path<(String, i64)>,
+) -> Result<HttpResponse, Error> {
+    let (id, invite) = path.into_inner();
+
+    db.revoke_invite(user, id, invite)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/{id}/category")]
+async fn set_category(
+    db: Data<Db>,
+    user: User,
+    id: web::Path<String>,
+    params: web::Json<CategoryParams>,
+) -> Result<HttpResponse, Error> {
+    db.set_team_category(user, id.into_inner(), params.into_inner().category)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+const MAX_FILM_NAME_LENGTH: usize = 100;
+const MAX_FILM_DESCRIPTION_LENGTH: usize = 2000;
+
+#[get("/{id}/film")]
+async fn get_film(db: Data<Db>, user: User, id: web::Path<String>) -> Result<HttpResponse, Error> {
+    db.get_film(user, id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/{id}/film")]
+async fn update_film_details(
+    db: Data<Db>,
+    user: User,
+    id: web::Path<String>,
+    params: web::Json<FilmDetailsParams>,
+) -> Result<HttpResponse, Error> {
+    let FilmDetailsParams {
+        film_name,
+        film_description,
+        submit,
+    } = params.into_inner();
+    let film_name = film_name.trim().to_owned();
+    let film_description = film_description.trim().to_owned();
+
+    if film_name.chars().count() > MAX_FILM_NAME_LENGTH {
+        return Err(Error::InvalidFilmDetails(format!(
+            "the name can't be longer than {MAX_FILM_NAME_LENGTH} characters"
+        )));
+    }
+    if film_description.chars().count() > MAX_FILM_DESCRIPTION_LENGTH {
+        return Err(Error::InvalidFilmDetails(format!(
+            "the description can't be longer than {MAX_FILM_DESCRIPTION_LENGTH} characters"
+        )));
+    }
+
+    db.update_film_details(user, id.into_inner(), film_name, film_description, submit)
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[get("/{id}/film/upload")]
+async fn get_film_upload(
+    db: Data<Db>,
+    user: User,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    db.get_film_upload(user, id.into_inner())
+        .await
+        .map(|x| HttpResponse::Ok().json(x))
+}
+
+#[post("/{id}/film/upload")]
+async fn start_film_upload(
+    db: Data<Db>,
+    store: Data<FilmStore>,
+    config: Data<FilmConfig>,
+    user: User,
+    id: web::Path<String>,
+    params: web::Query<StartUploadParams>,
+) -> Result<HttpResponse, Error> {
+    let id = id.into_inner();
+    let StartUploadParams {
+        file_name,
+        size,
+        checksum,
+    } = params.into_inner();
+
+    let file_name = file_name.trim().to_owned();
+    if file_name.is_empty()
+        || file_name.len() > 255
+        || file_name.contains(|x: char| x == '/' || x == '\\' || x.is_control())
+    {
+        return Err(Error::InvalidUpload("invalid file name".into()));
+    }
+    if size <= 0 || size > config.max_size {
+        return Err(Error::InvalidUpload(format!(
+            "films must be between 1 and {} bytes",
+            config.max_size
+        )));
+    }
+    if checksum.len() != 64 || !checksum.chars().all(|x| x.is_ascii_hexdigit()) {
+        return Err(Error::InvalidUpload(
+            "the checksum must be a hex encoded SHA-256".into(),
+        ));
+    }
+
+    let upload = db
+        .start_film_upload(user, id.clone(), file_name, size, checksum.to_lowercase())
+        .await?;
+
+    if upload.received == 0 {
+        store.discard_partial(&id).await?;
+    }
+
+    Ok(HttpResponse::Ok().json(upload))
+}
+
+#[post("/{id}/film/upload/chunk")]
+async fn upload_film_chunk(
+    db: Data<Db>,
+    store: Data<FilmStore>,
+    config: Data<FilmConfig>,
+    user: User,
+    id: web::Path<String>,
+    params: web::Query<ChunkParams>,
+    chunk: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let id = id.into_inner();
+    let mut lock = db.lock_film_upload(user, id.clone()).await?;
+    let FilmUpload { received, size, .. } = lock.upload;
+
+    if params.offset != received {
+        return Err(Error::UploadOffsetMismatch(received));
+    }
+
+    let on_disk = store.partial_len(&id).await? as i64;
+    if on_disk < received {
+        warn!("Upload for {id} is missing data, rewinding from {received} to {on_disk}");
+        lock.set_received(on_disk).await?;
+        return Err(Error::UploadOffsetMismatch(on_disk));
+    }
+
+    let limit = (size - received).min(config.max_chunk_size);
+    let received = store
+        .write_chunk(&id, received as u64, limit as u64, lock.deadline, chunk)
+        .await? as i64;
+
+    if received < size {
+        return lock
+            .set_received(received)
+            .await
+            .map(|x| HttpResponse::Ok().json(x));
+    }
+
+    // Checking the film can take longer than the lease, and nothing can be written to it now
+    lock.save_received(received).await?;
+
+    let verified = if store.checksum_partial(&id).await? != lock.upload.checksum {
+        warn!("Upload for {id} didn't match its checksum");
+        Err(Error::ChecksumMismatch)
+    } else {
+        store.probe_partial(&id).await.and_then(|info| {
+            let overlength = info.duration > config.max_duration + config.duration_grace;
+            if overlength && config.reject_overlength {
+                Err(Error::FilmTooLong(info.duration, config.max_duration))
+            } else {
+                Ok((info, overlength))
+            }
+        })
+    };
+
+    let (info, overlength) = match verified {
+        Ok(verified) => verified,
+        Err(
+            ex @ (Error::ChecksumMismatch | Error::UnsupportedFilm(_) | Error::FilmTooLong(..)),
+        ) => {
+            // Resuming would only give back the same file, so the upload starts again from scratch
+            store.discard_partial(&id).await?;
+            lock.set_received(0).await?;
+            return Err(ex);
+        }
+        Err(ex) => return Err(ex),
+    };
+
+    if overlength {
+        warn!("Film for {id} is {} seconds long", info.duration);
+    }
+
+    let upload = lock.upload.clone();
+    // Nothing refers to the stored film until the upload is completed, so downloads keep getting
+    // the old one whole until then
+    let key = store.commit(&id).await?;
+    match lock.complete(&key, info, overlength).await {
+        Ok(replaced) => {
+            if let Some(replaced) = replaced {
+                if store.remove_film(&replaced).await.is_err() {
+                    warn!("Couldn't remove the film {replaced} replaced by {key}");
+                }
+            }
+        }
+        Err(ex) => {
+            if store.remove_film(&key).await.is_err() {
+                warn!("Couldn't remove the film {key} which failed to upload");
+            }
+            return Err(ex);
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(upload))
+}
+
+#[get("/{id}/film/download")]
+async fn download_film(
+    db: Data<Db>,
+    store: Data<FilmStore>,
+    user: User,
+    id: web::Path<String>,
+    params: web::Query<DownloadParams>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let id = id.into_inner();
+    let film = db.get_film(user, id.clone()).await?;
+    let size = film.size as u64;
+    let etag = EntityTag::new_strong(film.checksum.clone());
+
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|x| x.weak_eq(&etag)),
+        None => false,
+    };
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .finish());
+    }
+
+    // A range is only valid if the client's copy is still the film we have now
+    let range_matches = match req.get_header::<IfRange>() {
+        Some(IfRange::EntityTag(tag)) => tag.strong_eq(&etag),
+        Some(IfRange::Date(_)) => false,
+        None => true,
+    };
+    let range = match req.get_header::<Range>() {
+        // Multiple ranges aren't worth supporting for films, so those just get the whole file
+        Some(Range::Bytes(ranges)) if range_matches && ranges.len() == 1 => Some(
+            ranges[0]
+                .to_satisfiable_range(size)
+                .ok_or(Error::RangeNotSatisfiable(film.size))?,
+        ),
+        _ => None,
+    };
+
+    let mut response = match range {
+        Some(range) => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some(range),
+                instance_length: Some(size),
+            }));
+            response
+        }
+        None => HttpResponse::Ok(),
+    };
+    let (start, end) = range.unwrap_or((0, size.saturating_sub(1)));
+    let len = if size == 0 { 0 } else { end - start + 1 };
+
+    response
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(ETag(etag))
+        .insert_header(ContentType(
+            films::content_type(&film.file_name).parse().unwrap(),
+        ))
+        .insert_header(ContentDisposition {
+            disposition: if params.inline {
+                DispositionType::Inline
+            } else {
+                DispositionType::Attachment
+            },
+            parameters: vec![DispositionParam::Filename(film.file_name)],
+        });
+
+    Ok(response
+        .no_chunking(len)
+        .streaming(store.read(&film.storage_key, start, len).await?))
+}
@@ -1,22 +1,24 @@
-use crate::{data::Auth0Config, db::Db, error::*};
-use actix_web::{web::Data, FromRequest};
-use futures_util::future::LocalBoxFuture;
-use jsonwebtoken::{
-    decode,
-    jwk::{AlgorithmParameters, JwkSet},
-    Algorithm, DecodingKey, Validation,
+use crate::{
+    data::{CookiePolicy, OidcConfig},
+    db::{Db, Permission, SessionRefresh},
+    dev_auth::DevIssuer,
+    error::*,
+    oidc::Provider,
 };
+use actix_web::{web::Data, FromRequest, HttpRequest};
+use chrono::{DateTime, Duration, Utc};
+use futures_util::future::LocalBoxFuture;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
 use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
 use tracing::warn;
 
-async fn parse_jwt(
-    jwks: &JwkSet,
+pub async fn parse_jwt(
+    provider: &Provider,
     token: &str,
-    Auth0Config {
-        domain, client_id, ..
-    }: &Auth0Config,
+    OidcConfig { client_id, .. }: &OidcConfig,
 ) -> Result<User, Error> {
-    let header = jwt::decode_header(&token).map_err(|_| {
+    let header = jwt::decode_header(token).map_err(|_| {
         warn!("Could not decode header");
         Error::Unauthorized
     })?;
@@ -24,25 +26,38 @@ async fn parse_jwt(
         warn!("No KIDs");
         Error::Unauthorized
     })?;
-    let jwk = jwks.find(&kid).ok_or_else(|| {
-        warn!("Could not find JWKs");
-        Error::Unauthorized
-    })?;
 
-    match jwk.clone().algorithm {
-        AlgorithmParameters::RSA(ref rsa) => {
-            let mut validation = Validation::new(Algorithm::RS256);
-            validation.set_audience(&[client_id]);
-            validation.set_issuer(&[format!("https://{domain}/").as_str()]);
-            let key = DecodingKey::from_rsa_components(&rsa.n, &rsa.e)
-                .map_err(|_| Error::Unauthorized)?;
-            let token =
-                decode::<User>(token, &key, &validation).map_err(|ex| Error::Unauthorized)?;
-
-            Ok(token.claims)
-        }
-        _ => Err(Error::Unauthorized),
+    let metadata = provider.metadata().await?;
+    // Shared secrets aren't supported, only keys from the provider's JWKs
+    let supported = metadata
+        .id_token_signing_alg_values_supported
+        .iter()
+        .filter_map(|x| x.parse::<Algorithm>().ok())
+        .filter(|x| !matches!(x, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512))
+        .any(|x| x == header.alg);
+    if !supported {
+        warn!("Unsupported algorithm {:?}", header.alg);
+        return Err(Error::Unauthorized);
+    }
+
+    let jwk = provider.find(&kid).await?;
+    if jwk
+        .common
+        .algorithm
+        .map(|x| x != header.alg)
+        .unwrap_or(false)
+    {
+        warn!("Token algorithm doesn't match its key");
+        return Err(Error::Unauthorized);
     }
+
+    let key = DecodingKey::from_jwk(&jwk).map_err(|_| Error::Unauthorized)?;
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[client_id]);
+    validation.set_issuer(&[metadata.issuer.as_str()]);
+    let token = decode::<User>(token, &key, &validation).map_err(|_| Error::Unauthorized)?;
+
+    Ok(token.claims)
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -51,8 +66,147 @@ pub struct User {
     pub id: String,
     pub name: String,
     pub email: String,
+    /// Whether the provider has checked they own `email`, which isn't the case for every login
     #[serde(default)]
-    pub is_admin: bool,
+    pub email_verified: bool,
+}
+
+/// Id tokens are refreshed when they have less than this many seconds left
+const REFRESH_MARGIN: i64 = 60;
+
+/// Longest to wait for another request to finish refreshing a session, in seconds
+const REFRESH_WAIT: i64 = 20;
+
+/// How often to check whether another request has finished refreshing a session
+const REFRESH_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// Gets the user from their session, refreshing its id token if it is about to expire. Nothing is
+/// kept locked while the provider is asked for new tokens, other requests for the session wait
+/// for them without holding a connection.
+async fn session_user(req: &HttpRequest, token: &str) -> Result<User, Error> {
+    let db = req.app_data::<Data<Db>>().unwrap().as_ref();
+    let oidc_config = req.app_data::<Data<OidcConfig>>().unwrap().as_ref();
+    let provider = req.app_data::<Data<Provider>>().unwrap().as_ref();
+    let fresh = |expires: DateTime<Utc>| expires > Utc::now() + Duration::seconds(REFRESH_MARGIN);
+    let wait_until = Utc::now() + Duration::seconds(REFRESH_WAIT);
+
+    loop {
+        let session = db.use_session(token).await?.ok_or_else(|| {
+            warn!("Unknown or expired session");
+            Error::Unauthorized
+        })?;
+        if fresh(session.id_token_expires) {
+            return parse_jwt(provider, &session.id_token, oidc_config).await;
+        }
+
+        if let Some(refresh) = db.claim_session_refresh(session.id).await? {
+            return refresh_session(provider, oidc_config, refresh).await;
+        }
+
+        // Someone else is refreshing it, the old id token will do if it hasn't quite expired
+        if session.id_token_expires > Utc::now() {
+            return parse_jwt(provider, &session.id_token, oidc_config).await;
+        }
+        if Utc::now() > wait_until {
+            warn!("Gave up waiting for session {} to be refreshed", session.id);
+            return Err(Error::AuthUnavailable);
+        }
+
+        tokio::time::sleep(REFRESH_POLL_INTERVAL).await;
+    }
+}
+
+/// Swaps the session's refresh token for new tokens, ending the session if the provider refuses
+async fn refresh_session(
+    provider: &Provider,
+    oidc_config: &OidcConfig,
+    refresh: SessionRefresh,
+) -> Result<User, Error> {
+    let Some(refresh_token) = refresh.tokens.refresh_token.clone() else {
+        refresh.end().await?;
+        return Err(Error::Unauthorized);
+    };
+
+    let tokens = match provider
+        .request_tokens(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token.as_str()),
+        ])
+        .await
+    {
+        Ok(tokens) => tokens,
+        Err(Error::Unauthorized) => {
+            warn!("Refresh token was refused, ending session");
+            refresh.end().await?;
+            return Err(Error::Unauthorized);
+        }
+        Err(ex) => return Err(ex),
+    };
+
+    let user = parse_jwt(provider, &tokens.id_token, oidc_config).await?;
+    refresh
+        .update(
+            tokens.id_token,
+            Utc::now() + Duration::seconds(tokens.expires_in),
+            // Providers which rotate refresh tokens send a new one
+            tokens.refresh_token.or(Some(refresh_token)),
+        )
+        .await?;
+
+    Ok(user)
+}
+
+/// Checks the user's session when logging in with the OIDC provider, or their token signed with
+/// our own key when development login is enabled. The frontend passes sessions on as
+/// `Authorization: Session {token}`. Raw id tokens aren't accepted, as they would outlive logging
+/// out and sessions being ended.
+async fn identify(req: &HttpRequest) -> Result<User, Error> {
+    let authorization = req
+        .headers()
+        .get("Authorization")
+        .and_then(|x| x.to_str().ok());
+
+    if let Some(issuer) = req.app_data::<Data<DevIssuer>>() {
+        let token = req
+            .cookie("access_token")
+            .map(|x| x.value().to_owned())
+            .or_else(|| authorization.map(|x| x.to_owned()))
+            .ok_or_else(|| {
+                warn!("No header");
+                Error::Unauthorized
+            })?;
+
+        return issuer.verify(&token);
+    }
+
+    let cookies = req.app_data::<Data<CookiePolicy>>().unwrap();
+    let session = req
+        .cookie(&cookies.name)
+        .map(|x| x.value().to_owned())
+        .or_else(|| {
+            authorization
+                .and_then(|x| x.strip_prefix("Session "))
+                .map(|x| x.to_owned())
+        })
+        .ok_or_else(|| {
+            warn!("No session");
+            Error::Unauthorized
+        })?;
+
+    session_user(req, &session).await
+}
+
+/// Identifies the user, as long as their account hasn't been disabled
+async fn authenticate(req: &HttpRequest) -> Result<User, Error> {
+    let db = req.app_data::<Data<Db>>().unwrap().as_ref();
+    let user = identify(req).await?;
+
+    if db.is_user_disabled(&user.id).await? {
+        warn!("User {} is disabled", user.id);
+        return Err(Error::AccountDisabled);
+    }
+
+    Ok(user)
 }
 
 impl FromRequest for User {
@@ -60,61 +214,56 @@ impl FromRequest for User {
 
     type Future = LocalBoxFuture<'static, Result<Self, Error>>;
 
-    fn from_request(req: &actix_web::HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
         let req = req.clone();
-        Box::pin(async move {
-            let auth0_config = req.app_data::<Data<Auth0Config>>().unwrap().as_ref();
-            let jwks = req.app_data::<Data<JwkSet>>().unwrap().as_ref();
-
-            let token = req
-                .cookie("access_token")
-                .map(|x| Some(x.value().to_owned()))
-                .unwrap_or_else(|| {
-                    req.headers()
-                        .get("Authorization")
-                        .and_then(|x| x.to_str().ok().map(|x| x.to_owned()))
-                })
-                .ok_or_else(|| {
-                    warn!("No header");
-                    Error::Unauthorized
-                })?;
-
-            parse_jwt(jwks, token.as_str(), auth0_config).await
-        })
+        Box::pin(async move { authenticate(&req).await })
     }
 }
 
-pub struct AdminUser(User);
+/// A permission an endpoint needs, see [`perm`]
+pub trait RequiredPermission {
+    const PERMISSION: Permission;
+}
 
-impl FromRequest for AdminUser {
+/// Types standing in for each [`Permission`], so they can be required with `Authorized<P>`
+pub mod perm {
+    use super::{Permission, RequiredPermission};
+
+    macro_rules! permissions {
+        ($($name:ident),*) => {
+            $(
+                pub struct $name;
+
+                impl RequiredPermission for $name {
+                    const PERMISSION: Permission = Permission::$name;
+                }
+            )*
+        };
+    }
+
+    permissions!(ViewTeams, EditTeams, ManageFestival, ManageUsers);
+}
+
+/// A user who has one of their roles give them the permission `P`, e.g.
+/// `Authorized<perm::ManageUsers>`
+pub struct Authorized<P>(pub User, PhantomData<P>);
+
+impl<P: RequiredPermission + 'static> FromRequest for Authorized<P> {
     type Error = Error;
 
     type Future = LocalBoxFuture<'static, Result<Self, Error>>;
 
-    fn from_request(req: &actix_web::HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
         let req = req.clone();
         Box::pin(async move {
-            let auth0_config = req.app_data::<Data<Auth0Config>>().unwrap().as_ref();
-            let jwks = req.app_data::<Data<JwkSet>>().unwrap().as_ref();
             let db = req.app_data::<Data<Db>>().unwrap().as_ref();
+            let user = authenticate(&req).await?;
 
-            let token = req
-                .cookie("access_token")
-                .map(|x| Some(x.value().to_owned()))
-                .unwrap_or_else(|| {
-                    req.headers()
-                        .get("Authorization")
-                        .and_then(|x| x.to_str().ok().map(|x| x.to_owned()))
-                })
-                .ok_or(Error::Unauthorized)?;
-
-            let user = parse_jwt(jwks, token.as_str(), auth0_config).await?;
-
-            if db.is_user_admin(&user).await.unwrap_or(false) {
-                Ok(AdminUser(user))
+            if db.has_permission(&user, P::PERMISSION).await? {
+                Ok(Authorized(user, PhantomData))
             } else {
-                warn!("User is not an admin");
-                Err(Error::Unauthorized)
+                warn!("User doesn't have the {:?} permission", P::PERMISSION);
+                Err(Error::NotAllowed)
             }
         })
     }
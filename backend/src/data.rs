@@ -1,6 +1,11 @@
-// use rand::{thread_rng, Rng};
-use serde::Deserialize;
-// use tracing::info;
+use actix_web::cookie::{time::Duration, Cookie, CookieBuilder, Key, SameSite};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use rand::{thread_rng, RngCore};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
 
 use crate::error::*;
 
@@ -10,49 +15,557 @@ pub struct UrlConfig {
     pub frontend: String,
 }
 
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSitePolicy {
+    Strict,
+    Lax,
+    None,
+}
+
 #[derive(Deserialize, Clone)]
-pub struct Auth0Config {
+pub struct CookieConfig {
+    /// Name of the session cookie, the frontend needs the same one in `PUBLIC_SESSION_COOKIE`
+    #[serde(default = "default_cookie_name")]
+    pub name: String,
+    /// Has to cover both the frontend and backend, and has to be set if they're on different hosts.
+    /// Without it the cookie is kept to the backend's host.
+    pub domain: Option<String>,
+    #[serde(default = "default_same_site")]
+    pub same_site: SameSitePolicy,
+    /// Defaults to whether the backend is served over HTTPS
+    pub secure: Option<bool>,
+    #[serde(default = "default_cookie_path")]
+    pub path: String,
+}
+
+fn default_cookie_name() -> String {
+    "session".into()
+}
+
+fn default_same_site() -> SameSitePolicy {
+    SameSitePolicy::Lax
+}
+
+fn default_cookie_path() -> String {
+    "/".into()
+}
+
+fn url_host(var: &str, url: &str) -> Result<String, Error> {
+    Url::parse(url)
+        .ok()
+        .and_then(|x| x.host_str().map(|x| x.to_lowercase()))
+        .ok_or_else(|| Error::InvalidConfig(format!("{var} isn't a valid URL")))
+}
+
+fn covers(domain: &str, host: &str) -> bool {
+    host == domain || host.ends_with(&format!(".{domain}"))
+}
+
+impl CookieConfig {
+    /// Checks the settings make sense together, filling in anything which wasn't set
+    pub fn validate(self, urls: &UrlConfig) -> Result<CookiePolicy, Error> {
+        let backend = url_host("PUBLIC_BACKEND", &urls.backend)?;
+        let frontend = url_host("PUBLIC_FRONTEND", &urls.frontend)?;
+
+        let domain = match self.domain {
+            Some(domain) => {
+                let domain = domain.trim_start_matches('.').to_lowercase();
+                for (var, host) in [("PUBLIC_BACKEND", &backend), ("PUBLIC_FRONTEND", &frontend)] {
+                    if !covers(&domain, host) {
+                        return Err(Error::InvalidConfig(format!(
+                            "COOKIE_DOMAIN {domain} doesn't cover {var} ({host})"
+                        )));
+                    }
+                }
+                Some(domain)
+            }
+            // Ports don't matter to cookies, so this covers localhost with different ports
+            None if backend == frontend => None,
+            // Deriving a domain from the hosts can land on a public suffix like github.io, which
+            // browsers refuse to set cookies for, so login would quietly fail
+            None => {
+                return Err(Error::InvalidConfig(format!(
+                    "the frontend ({frontend}) and backend ({backend}) are on different hosts, set COOKIE_DOMAIN"
+                )))
+            }
+        };
+
+        let secure = self
+            .secure
+            .unwrap_or_else(|| urls.backend.starts_with("https://"));
+        if self.same_site == SameSitePolicy::None && !secure {
+            return Err(Error::InvalidConfig(
+                "COOKIE_SAME_SITE=none needs secure cookies".into(),
+            ));
+        }
+        if !self.path.starts_with('/') {
+            return Err(Error::InvalidConfig(
+                "COOKIE_PATH has to start with /".into(),
+            ));
+        }
+        if self.name.is_empty()
+            || !self
+                .name
+                .chars()
+                .all(|x| x.is_ascii_alphanumeric() || x == '_' || x == '-')
+        {
+            return Err(Error::InvalidConfig(
+                "COOKIE_NAME can only have letters, numbers, - and _".into(),
+            ));
+        }
+
+        Ok(CookiePolicy {
+            name: self.name,
+            domain,
+            same_site: match self.same_site {
+                SameSitePolicy::Strict => SameSite::Strict,
+                SameSitePolicy::Lax => SameSite::Lax,
+                SameSitePolicy::None => SameSite::None,
+            },
+            secure,
+            path: self.path,
+        })
+    }
+}
+
+/// The checked cookie settings, used for the cookies people are logged in with
+#[derive(Clone)]
+pub struct CookiePolicy {
+    /// Name of the session cookie
+    pub name: String,
+    domain: Option<String>,
+    same_site: SameSite,
+    pub secure: bool,
+    path: String,
+}
+
+impl CookiePolicy {
+    /// A cookie with these settings, a `max_age` of 0 removes it
+    pub fn build(&self, name: String, value: String, max_age: i64) -> Cookie<'static> {
+        let mut cookie = CookieBuilder::new(name, value)
+            .max_age(Duration::seconds(max_age))
+            .http_only(true)
+            .path(self.path.clone())
+            .same_site(self.same_site)
+            .secure(self.secure)
+            .finish();
+
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+
+        cookie
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct OidcConfig {
+    /// The provider's issuer URL, its discovery document is found under
+    /// `{issuer}/.well-known/openid-configuration`
+    pub issuer: String,
     pub client_id: String,
     pub client_secret: String,
-    pub domain: String,
+    /// Space separated scopes requested when logging in
+    #[serde(default = "default_scopes")]
+    pub scopes: String,
+    /// Used to encrypt the login state cookie, has to be at least 32 bytes
+    pub state_secret: Option<String>,
+    /// Logout endpoint taking `client_id` and `returnTo`, for providers like Auth0 which don't
+    /// list one in their discovery document
+    pub logout_url: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct DevAuthConfig {
+    /// Issue our own tokens for made up users instead of using an OIDC provider. Anyone can log
+    /// in as anyone, so never turn this on in production.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Keeps tokens working across restarts
+    pub secret: Option<String>,
+}
+
+/// How people log in
+pub enum AuthConfig {
+    Oidc(OidcConfig),
+    Dev(DevAuthConfig),
+}
+
+fn default_scopes() -> String {
+    // offline_access asks for a refresh token, so sessions can outlive the id token
+    "openid profile email offline_access".into()
+}
+
+impl OidcConfig {
+    /// The key for the login state cookie. Without a secret a new key is made every time the
+    /// server starts, so anyone half way through logging in will have to start again.
+    pub fn state_key(&self) -> Result<Key, Error> {
+        match &self.state_secret {
+            Some(secret) if secret.len() < 32 => Err(Error::InvalidConfig(
+                "OIDC_STATE_SECRET has to be at least 32 bytes long".into(),
+            )),
+            Some(secret) => Ok(Key::derive_from(secret.as_bytes())),
+            None => {
+                warn!("OIDC_STATE_SECRET isn't set, using a random key");
+                Ok(Key::generate())
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct FilmConfig {
+    /// Largest film a team can upload, in bytes
+    #[serde(default = "default_max_size")]
+    pub max_size: i64,
+    /// Largest chunk of a film which can be sent at once, in bytes. Each chunk has to arrive
+    /// before the upload's lease runs out, so this can't be too big.
+    #[serde(default = "default_max_chunk_size")]
+    pub max_chunk_size: i64,
+    /// Longest a film can be, in seconds
+    #[serde(default = "default_max_duration")]
+    pub max_duration: f64,
+    /// How many seconds over `max_duration` a film can go before it is flagged
+    #[serde(default = "default_duration_grace")]
+    pub duration_grace: f64,
+    /// Reject films which are too long, instead of just flagging them
+    #[serde(default)]
+    pub reject_overlength: bool,
+}
+
+fn default_max_size() -> i64 {
+    // 8 GiB
+    8 * 1024 * 1024 * 1024
+}
+
+fn default_max_chunk_size() -> i64 {
+    // 64 MiB
+    64 * 1024 * 1024
+}
+
+fn default_max_duration() -> f64 {
+    10.0 * 60.0
+}
+
+fn default_duration_grace() -> f64 {
+    10.0
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TeamConfig {
+    /// How long a team can be empty before it is archived and its name freed up, in seconds
+    #[serde(default = "default_archive_after")]
+    pub archive_after: u64,
+}
+
+fn default_archive_after() -> u64 {
+    // A week
+    7 * 24 * 60 * 60
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Plain text, only for a local server
+    None,
+    /// Upgrades the connection with STARTTLS, usually on port 587
+    StartTls,
+    /// TLS from the start, usually on port 465
+    Tls,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SmtpConfig {
+    /// Emails are only logged when this isn't set, for development
+    pub host: Option<String>,
+    /// Defaults to the usual port for `security`
+    pub port: Option<u16>,
+    #[serde(default = "default_smtp_security")]
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Who emails are sent from, e.g. `NAFF <noreply@example.com>`
+    #[serde(default = "default_smtp_from")]
+    pub from: String,
+}
+
+fn default_smtp_security() -> SmtpSecurity {
+    SmtpSecurity::StartTls
+}
+
+fn default_smtp_from() -> String {
+    "NAFF <noreply@localhost>".into()
+}
+
+#[derive(Deserialize, Clone)]
+pub struct MailConfig {
+    /// How long before a deadline to remind teams about it, in seconds. 0 turns reminders off.
+    #[serde(default = "default_reminder_before")]
+    pub reminder_before: u64,
+}
+
+fn default_reminder_before() -> u64 {
+    // Two days
+    2 * 24 * 60 * 60
 }
 
-pub fn get_config() -> Result<(Auth0Config, UrlConfig), Error> {
-    let authz_config: Auth0Config = envy::prefixed("AUTH0_").from_env().to_crate()?;
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Local,
+    S3,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct StorageConfig {
+    #[serde(default = "default_storage_backend")]
+    pub backend: StorageBackend,
+    /// Where uploads are assembled, and where films are kept when using local storage
+    #[serde(default = "default_storage_path")]
+    pub path: String,
+    pub s3_bucket: Option<String>,
+    #[serde(default = "default_s3_region")]
+    pub s3_region: String,
+    /// Only needed for S3 compatible services other than AWS, like MinIO
+    pub s3_endpoint: Option<String>,
+    pub s3_access_key: Option<String>,
+    pub s3_secret_key: Option<String>,
+    #[serde(default)]
+    pub s3_path_style: bool,
+}
+
+fn default_storage_backend() -> StorageBackend {
+    StorageBackend::Local
+}
+
+fn default_storage_path() -> String {
+    "films".into()
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".into()
+}
+
+/// Everything configured through environment variables
+pub struct Config {
+    pub auth: AuthConfig,
+    pub urls: UrlConfig,
+    pub cookies: CookiePolicy,
+    pub film: FilmConfig,
+    pub storage: StorageConfig,
+    pub teams: TeamConfig,
+    pub smtp: SmtpConfig,
+    pub mail: MailConfig,
+}
+
+pub fn get_config() -> Result<Config, Error> {
+    let dev_auth_config: DevAuthConfig = envy::prefixed("DEV_AUTH_").from_env().to_crate()?;
+    let authz_config = if dev_auth_config.enabled {
+        AuthConfig::Dev(dev_auth_config)
+    } else {
+        AuthConfig::Oidc(envy::prefixed("OIDC_").from_env().to_crate()?)
+    };
     let public_config: UrlConfig = envy::prefixed("PUBLIC_").from_env().to_crate()?;
+    let cookie_config: CookieConfig = envy::prefixed("COOKIE_").from_env().to_crate()?;
+    let cookie_policy = cookie_config.validate(&public_config)?;
+    let film_config: FilmConfig = envy::prefixed("FILM_").from_env().to_crate()?;
+    let storage_config: StorageConfig = envy::prefixed("STORAGE_").from_env().to_crate()?;
+    let team_config: TeamConfig = envy::prefixed("TEAM_").from_env().to_crate()?;
+    let smtp_config: SmtpConfig = envy::prefixed("SMTP_").from_env().to_crate()?;
+    let mail_config: MailConfig = envy::prefixed("MAIL_").from_env().to_crate()?;
+
+    Ok(Config {
+        auth: authz_config,
+        urls: public_config,
+        cookies: cookie_policy,
+        film: film_config,
+        storage: storage_config,
+        teams: team_config,
+        smtp: smtp_config,
+        mail: mail_config,
+    })
+}
+
+/// How long someone has to finish logging in, in seconds
+pub const LOGIN_STATE_EXPIRY: i64 = 10 * 60;
 
-    Ok((authz_config, public_config))
-}
-
-// #[derive(Clone, Debug)]
-// pub struct States {
-//     inner: Vec<String>,
-// }
-
-// impl States {
-//     pub fn new() -> Self {
-//         Self { inner: vec![] }
-//     }
-
-//     pub fn add(&mut self) -> String {
-//         let mut rng = thread_rng();
-//         let state: String = (0..10)
-//             .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
-//             .collect();
-//         self.inner.push(state.clone());
-
-//         state
-//     }
-
-//     pub fn check(&mut self, state: &String) -> bool {
-//         info!("{state} {:?}", self.inner);
-//         let find_index = self.inner.iter().position(|x| x == state);
-//         if let Some(idex) = find_index {
-//             self.inner.remove(idex);
-//             return true;
-//         }
-
-//         false
-//     }
-// }
-//
+/// Kept in an encrypted cookie while someone logs in with the provider, so the callback can check that
+/// it came from a login started in the same browser
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LoginState {
+    /// Sent to the provider and handed back to the callback untouched
+    pub state: String,
+    /// PKCE code verifier, only its hash is sent when the login starts
+    pub verifier: String,
+    /// Unix timestamp
+    pub expires: i64,
+    /// Token of the team invite they followed to get here, accepted once they're logged in
+    #[serde(default)]
+    pub invite: Option<String>,
+}
+
+impl LoginState {
+    pub fn generate() -> Self {
+        Self {
+            state: random_token(),
+            verifier: random_token(),
+            expires: Utc::now().timestamp() + LOGIN_STATE_EXPIRY,
+            invite: None,
+        }
+    }
+
+    /// The PKCE code challenge, using the S256 method
+    pub fn challenge(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(self.verifier.as_bytes()))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Utc::now().timestamp() >= self.expires
+    }
+}
+
+/// 256 random bits, URL safe
+pub fn random_token() -> String {
+    let mut bytes = [0; 32];
+    thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn urls(backend: &str, frontend: &str) -> UrlConfig {
+        UrlConfig {
+            backend: backend.into(),
+            frontend: frontend.into(),
+        }
+    }
+
+    fn cookies(domain: Option<&str>, same_site: SameSitePolicy) -> CookieConfig {
+        CookieConfig {
+            name: default_cookie_name(),
+            domain: domain.map(Into::into),
+            same_site,
+            secure: None,
+            path: default_cookie_path(),
+        }
+    }
+
+    fn assert_invalid(result: Result<CookiePolicy, Error>, message: &str) {
+        match result {
+            Err(Error::InvalidConfig(x)) => assert!(x.contains(message), "unexpected error {x}"),
+            Err(ex) => panic!("expected a config error, got {ex:?}"),
+            Ok(_) => panic!("expected the cookie settings to be rejected"),
+        }
+    }
+
+    #[test]
+    fn same_host_keeps_cookie_to_it() {
+        let policy = cookies(None, SameSitePolicy::Lax)
+            .validate(&urls("http://localhost:8080", "http://localhost:5173"))
+            .unwrap();
+
+        assert_eq!(policy.domain, None);
+        assert!(!policy.secure);
+    }
+
+    #[test]
+    fn different_hosts_need_domain() {
+        // These only share github.io, which browsers won't set cookies for
+        assert_invalid(
+            cookies(None, SameSitePolicy::Lax)
+                .validate(&urls("https://a.github.io", "https://b.github.io")),
+            "set COOKIE_DOMAIN",
+        );
+        assert_invalid(
+            cookies(None, SameSitePolicy::Lax)
+                .validate(&urls("https://api.example.com", "https://example.com")),
+            "set COOKIE_DOMAIN",
+        );
+    }
+
+    #[test]
+    fn explicit_domain_covering_both() {
+        let policy = cookies(Some(".Example.com"), SameSitePolicy::Lax)
+            .validate(&urls("https://api.example.com", "https://example.com"))
+            .unwrap();
+
+        assert_eq!(policy.domain.as_deref(), Some("example.com"));
+        assert!(policy.secure);
+    }
+
+    #[test]
+    fn explicit_domain_not_covering_host() {
+        assert_invalid(
+            cookies(Some("api.example.com"), SameSitePolicy::Lax)
+                .validate(&urls("https://api.example.com", "https://example.com")),
+            "doesn't cover PUBLIC_FRONTEND",
+        );
+        // Only whole labels count, so this isn't a match for example.com
+        assert_invalid(
+            cookies(Some("ample.com"), SameSitePolicy::Lax)
+                .validate(&urls("https://example.com", "https://example.com")),
+            "doesn't cover PUBLIC_BACKEND",
+        );
+    }
+
+    #[test]
+    fn same_site_none_needs_secure() {
+        assert_invalid(
+            cookies(None, SameSitePolicy::None)
+                .validate(&urls("http://localhost:8080", "http://localhost:5173")),
+            "needs secure cookies",
+        );
+
+        let mut config = cookies(None, SameSitePolicy::None);
+        config.secure = Some(false);
+        assert_invalid(
+            config.validate(&urls("https://example.com", "https://example.com")),
+            "needs secure cookies",
+        );
+
+        let policy = cookies(None, SameSitePolicy::None)
+            .validate(&urls("https://example.com", "https://example.com"))
+            .unwrap();
+        assert_eq!(policy.same_site, SameSite::None);
+    }
+
+    #[test]
+    fn pkce_challenge() {
+        // The example from RFC 7636, appendix B
+        let state = LoginState {
+            verifier: "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".into(),
+            ..LoginState::generate()
+        };
+
+        assert_eq!(
+            state.challenge(),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn login_state_is_random() {
+        let (a, b) = (LoginState::generate(), LoginState::generate());
+
+        assert_ne!(a.state, b.state);
+        assert_ne!(a.verifier, b.verifier);
+        assert_ne!(a.state, a.verifier);
+    }
+
+    #[test]
+    fn login_state_expires() {
+        assert!(!LoginState::generate().is_expired());
+
+        let now = Utc::now().timestamp();
+        for expires in [now, now - LOGIN_STATE_EXPIRY] {
+            let state = LoginState {
+                expires,
+                ..LoginState::generate()
+            };
+            assert!(state.is_expired());
+        }
+    }
+}
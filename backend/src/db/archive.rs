@@ -0,0 +1,121 @@
+use super::{audit::record, commit, join_codes::new_join_code, teams::lock_team, Db, Team};
+use crate::error::*;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{borrow::Cow, time::Duration};
+use tracing::{error, info};
+
+/// Longest to wait between looking for teams to archive
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Shortest wait between sweeps, so a tiny `archive_after` doesn't keep the database busy
+const MIN_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Someone joining or leaving a team
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MembershipChange {
+    pub id: i64,
+    pub team: String,
+    pub user: String,
+    /// Whether they joined or left
+    pub joined: bool,
+    /// Who put them in or took them out, which is usually the user themselves
+    pub actor: String,
+    pub created: DateTime<Utc>,
+}
+
+/// Archives teams once they have been empty for `after`, for as long as the server is running
+pub fn keep_archiving(db: Db, after: Duration) {
+    tokio::spawn(async move {
+        loop {
+            match db.archive_empty_teams(after).await {
+                Ok(archived) if !archived.is_empty() => {
+                    info!("Archived empty teams {}", archived.join(", "))
+                }
+                // Already logged, it'll be tried again next time
+                _ => {}
+            }
+
+            tokio::time::sleep(after.clamp(MIN_SWEEP_INTERVAL, SWEEP_INTERVAL)).await;
+        }
+    });
+}
+
+impl Db {
+    /// Archives the teams which have been empty for longer than `after`, which frees up their
+    /// names and stops their join codes working. Nobody is left to accept requests to join them,
+    /// so those are dropped. Returns the ids of the teams archived.
+    pub async fn archive_empty_teams(&self, after: Duration) -> Result<Vec<String>, Error> {
+        sqlx::query_scalar!(
+            r#"WITH archived AS (
+    UPDATE teams SET archived = now() WHERE archived IS NULL AND emptied < now() - make_interval(secs => $1) RETURNING id
+), codes AS (
+    DELETE FROM join_codes WHERE team IN (SELECT id FROM archived)
+), requests AS (
+    DELETE FROM join_requests WHERE team IN (SELECT id FROM archived)
+)
+SELECT id FROM archived"#,
+            after.as_secs_f64()
+        )
+        .fetch_all(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error archiving empty teams {x}");
+            Error::InternalError
+        })
+    }
+
+    /// Brings an archived team back with a new join code, as long as nobody has taken its name
+    pub async fn restore_team(&self, actor: &str, id: String) -> Result<Team, Error> {
+        let mut transaction = self.begin().await?;
+        let old = lock_team(&mut transaction, &id).await?;
+        if old.archived.is_none() {
+            return Err(Error::InvalidTeamChange(format!("{id} isn't archived")));
+        }
+
+        // It gets the whole grace period again, or someone could restore it just before the
+        // next sweep archives it again
+        let team = sqlx::query_as!(
+            Team,
+            "UPDATE teams SET archived = NULL, emptied = CASE WHEN emptied IS NULL THEN NULL ELSE now() END WHERE id = $1 RETURNING *",
+            id
+        )
+        .fetch_one(&mut transaction)
+        .await
+        .map_err(|x| match x {
+            // unique_violation, another team has the name now
+            sqlx::Error::Database(ex) if ex.code() == Some(Cow::from("23505")) => {
+                Error::TeamNameTaken(old.name.clone())
+            }
+            _ => {
+                error!("Error restoring team {x}");
+                Error::InternalError
+            }
+        })?;
+        new_join_code(&mut transaction, &id, None, false).await?;
+
+        record(&mut transaction, actor, "restore_team", &id, json!({})).await?;
+        commit(transaction).await?;
+
+        Ok(team)
+    }
+
+    /// Everyone who has joined or left the team, oldest first
+    pub async fn get_membership_changes(
+        &self,
+        team_id: String,
+    ) -> Result<Vec<MembershipChange>, Error> {
+        sqlx::query_as!(
+            MembershipChange,
+            r#"SELECT id, team, "user", joined, actor, created FROM membership_changes WHERE team = $1 ORDER BY id"#,
+            team_id
+        )
+        .fetch_all(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching membership changes {x}");
+            Error::InternalError
+        })
+    }
+}
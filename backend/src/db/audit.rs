@@ -0,0 +1,62 @@
+use super::Db;
+use crate::error::*;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Postgres, Transaction};
+use tracing::error;
+
+/// A change made to a user, role or team
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    /// The user who made the change
+    pub actor: String,
+    pub action: String,
+    /// The id of the user, role or team which was changed
+    pub subject: String,
+    pub details: Value,
+    pub created: DateTime<Utc>,
+}
+
+/// Records a change in the same transaction as it is made, so one can't happen without the other
+pub(super) async fn record(
+    transaction: &mut Transaction<'static, Postgres>,
+    actor: &str,
+    action: &str,
+    subject: &str,
+    details: Value,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r#"INSERT INTO audit_log (actor, "action", subject, details) VALUES ($1, $2, $3, $4)"#,
+        actor,
+        action,
+        subject,
+        details
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|x| {
+        error!("Error recording change {x}");
+        Error::InternalError
+    })?;
+
+    Ok(())
+}
+
+impl Db {
+    /// The newest changes first, optionally only those made to one user, role or team
+    pub async fn get_audit_log(&self, subject: Option<String>) -> Result<Vec<AuditEntry>, Error> {
+        sqlx::query_as!(
+            AuditEntry,
+            r#"SELECT id, actor, "action", subject, details, created FROM audit_log WHERE $1::TEXT IS NULL OR subject = $1 ORDER BY id DESC"#,
+            subject
+        )
+        .fetch_all(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching audit log {x}");
+            Error::InternalError
+        })
+    }
+}
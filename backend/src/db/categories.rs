@@ -0,0 +1,166 @@
+use super::{
+    commit,
+    teams::{check_team_size, lock_team},
+    Db, Deadline, Permission, Team,
+};
+use crate::{auth::User as AuthUser, error::*};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use tracing::error;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Category {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    /// Eligibility rules, shown to teams
+    pub rules: String,
+    /// Everyone in the team needs an email address at this domain
+    pub email_domain: Option<String>,
+    /// The most people a team in the category can have, instead of the festival wide limit
+    pub max_team_size: Option<i32>,
+}
+
+impl Category {
+    /// Checks if someone with this email address can be in a team entered in the category
+    pub fn check_eligible(&self, email: &str) -> Result<(), Error> {
+        match &self.email_domain {
+            Some(domain)
+                if !email
+                    .rsplit_once('@')
+                    .map(|(_, x)| x.eq_ignore_ascii_case(domain))
+                    .unwrap_or(false) =>
+            {
+                Err(Error::NotEligible(
+                    self.name.clone(),
+                    format!("everyone in the team needs an @{domain} email address"),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Db {
+    pub async fn get_categories(&self) -> Result<Vec<Category>, Error> {
+        sqlx::query_as!(
+            Category,
+            r#"SELECT id, "name", description, rules, email_domain, max_team_size FROM categories ORDER BY id"#
+        )
+        .fetch_all(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching categories {x}");
+            Error::InternalError
+        })
+    }
+
+    pub async fn get_category(&self, id: String) -> Result<Category, Error> {
+        sqlx::query_as!(
+            Category,
+            r#"SELECT id, "name", description, rules, email_domain, max_team_size FROM categories WHERE id = $1"#,
+            id
+        )
+        .fetch_optional(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching category {x}");
+            Error::InternalError
+        })?
+        .ok_or(Error::NoSuchCategory(id))
+    }
+
+    /// Creates a category, or replaces the one with the same id
+    pub async fn set_category(&self, category: Category) -> Result<Category, Error> {
+        sqlx::query_as!(
+            Category,
+            r#"INSERT INTO categories (id, "name", description, rules, email_domain, max_team_size) VALUES ($1, $2, $3, $4, $5, $6)
+ON CONFLICT (id) DO UPDATE SET "name" = EXCLUDED."name", description = EXCLUDED.description, rules = EXCLUDED.rules, email_domain = EXCLUDED.email_domain, max_team_size = EXCLUDED.max_team_size
+RETURNING id, "name", description, rules, email_domain, max_team_size"#,
+            category.id,
+            category.name,
+            category.description,
+            category.rules,
+            category.email_domain,
+            category.max_team_size
+        )
+        .fetch_one(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error setting category {x}");
+            Error::InternalError
+        })
+    }
+
+    pub async fn remove_category(&self, id: String) -> Result<(), Error> {
+        let result = sqlx::query!("DELETE FROM categories WHERE id = $1", id)
+            .execute(&self.connection)
+            .await
+            .map_err(|x| match x {
+                // foreign_key_violation, there are still teams in it
+                sqlx::Error::Database(ex) if ex.code() == Some(Cow::from("23503")) => {
+                    Error::CategoryInUse(id.clone())
+                }
+                _ => {
+                    error!("Error removing category {x}");
+                    Error::InternalError
+                }
+            })?;
+
+        if result.rows_affected() == 0 {
+            Err(Error::NoSuchCategory(id))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Moves a team into a category, as long as everyone in the team is eligible for it and the
+    /// team isn't too big for it
+    pub async fn set_team_category(
+        &self,
+        user: AuthUser,
+        team_code: String,
+        category: String,
+    ) -> Result<Team, Error> {
+        self.in_specific_team(user, team_code.clone(), Permission::EditTeams)
+            .await?;
+        self.check_deadline(&team_code, Deadline::Edits).await?;
+
+        let category = self.get_category(category).await?;
+
+        let mut transaction = self.begin().await?;
+        lock_team(&mut transaction, &team_code).await?;
+
+        let emails = sqlx::query_scalar!(
+            "SELECT u.email FROM user_connection JOIN users u ON user_connection.\"user\" = u.id WHERE team = $1",
+            team_code
+        )
+        .fetch_all(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error fetching team emails {x}");
+            Error::InternalError
+        })?;
+
+        for email in emails {
+            category.check_eligible(&email)?;
+        }
+        check_team_size(&mut transaction, &team_code, Some(&category.id), 0).await?;
+
+        let team = sqlx::query_as!(
+            Team,
+            "UPDATE teams SET category = $2 WHERE id = $1 RETURNING *",
+            team_code,
+            category.id
+        )
+        .fetch_one(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error setting team category {x}");
+            Error::InternalError
+        })?;
+        commit(transaction).await?;
+
+        Ok(team)
+    }
+}
@@ -0,0 +1,310 @@
+use super::{audit::record, commit, teams::lock_team, Db, Deadline, User};
+use crate::{auth::User as AuthUser, error::*, mail::Email};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::PgConnection;
+use std::time::Duration;
+use tracing::{error, warn};
+
+/// How many times to try sending an email before giving up on it
+const MAX_ATTEMPTS: i32 = 8;
+
+/// How long an email is left alone while it's being sent, in case the server stops part way
+const SEND_LEASE_SECS: f64 = 5.0 * 60.0;
+
+/// An email in the queue
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueuedEmail {
+    pub id: i64,
+    pub user: Option<String>,
+    pub address: String,
+    pub email: Value,
+    pub attempts: i32,
+    /// When it'll next be tried, unless it has been sent or given up on
+    pub next_attempt: Option<DateTime<Utc>>,
+    pub sent: Option<DateTime<Utc>>,
+    pub created: DateTime<Utc>,
+}
+
+fn email_json(email: &Email) -> Result<Value, Error> {
+    serde_json::to_value(email).map_err(|x| {
+        error!("Error serialising email {x}");
+        Error::InternalError
+    })
+}
+
+/// Queues the email for the user, unless they've opted out
+pub(super) async fn queue_email(
+    connection: &mut PgConnection,
+    user_id: &str,
+    email: &Email,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r#"INSERT INTO email_queue ("user", address, email) SELECT id, email, $2 FROM users WHERE id = $1 AND NOT email_opt_out"#,
+        user_id,
+        email_json(email)?
+    )
+    .execute(&mut *connection)
+    .await
+    .map_err(|x| {
+        error!("Error queueing email {x}");
+        Error::InternalError
+    })?;
+
+    Ok(())
+}
+
+/// Queues the email for everyone in the team who hasn't opted out, except `except`
+pub(super) async fn queue_team_email(
+    connection: &mut PgConnection,
+    team_id: &str,
+    except: Option<&str>,
+    email: &Email,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r#"INSERT INTO email_queue ("user", address, email)
+SELECT u.id, u.email, $3 FROM user_connection c JOIN users u ON u.id = c."user"
+WHERE c.team = $1 AND u.id IS DISTINCT FROM $2 AND NOT u.email_opt_out"#,
+        team_id,
+        except,
+        email_json(email)?
+    )
+    .execute(&mut *connection)
+    .await
+    .map_err(|x| {
+        error!("Error queueing team email {x}");
+        Error::InternalError
+    })?;
+
+    Ok(())
+}
+
+impl Db {
+    /// Takes up to `limit` emails which are due to be sent, leaving the rest for other servers
+    pub async fn claim_emails(&self, limit: i64) -> Result<Vec<QueuedEmail>, Error> {
+        sqlx::query_as!(
+            QueuedEmail,
+            r#"UPDATE email_queue SET next_attempt = now() + make_interval(secs => $2)
+WHERE id IN (SELECT id FROM email_queue WHERE next_attempt <= now() ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED)
+RETURNING *"#,
+            limit,
+            SEND_LEASE_SECS
+        )
+        .fetch_all(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error claiming emails {x}");
+            Error::InternalError
+        })
+    }
+
+    pub async fn email_sent(&self, id: i64) -> Result<(), Error> {
+        sqlx::query!(
+            "UPDATE email_queue SET sent = now(), next_attempt = NULL, attempts = attempts + 1 WHERE id = $1",
+            id
+        )
+        .execute(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error marking email as sent {x}");
+            Error::InternalError
+        })?;
+
+        Ok(())
+    }
+
+    /// Tries the email again later, waiting twice as long each time, or gives up on it if it
+    /// can't ever be sent or has failed too many times
+    pub async fn email_failed(&self, id: i64, permanent: bool) -> Result<(), Error> {
+        let next_attempt = sqlx::query_scalar!(
+            r#"UPDATE email_queue SET attempts = attempts + 1,
+    next_attempt = CASE WHEN $2 OR attempts + 1 >= $3 THEN NULL ELSE now() + make_interval(mins => power(2, attempts)::INTEGER) END
+WHERE id = $1 RETURNING next_attempt"#,
+            id,
+            permanent,
+            MAX_ATTEMPTS
+        )
+        .fetch_one(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error marking email as failed {x}");
+            Error::InternalError
+        })?;
+
+        if next_attempt.is_none() {
+            warn!("Giving up on email {id}");
+        }
+
+        Ok(())
+    }
+
+    /// Emails which haven't been sent, including ones which have been given up on
+    pub async fn get_unsent_emails(&self) -> Result<Vec<QueuedEmail>, Error> {
+        sqlx::query_as!(
+            QueuedEmail,
+            "SELECT * FROM email_queue WHERE sent IS NULL ORDER BY id"
+        )
+        .fetch_all(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching unsent emails {x}");
+            Error::InternalError
+        })
+    }
+
+    /// Tries an unsent email again straight away, e.g. once the mail server has been fixed
+    pub async fn retry_email(&self, actor: &str, id: i64) -> Result<QueuedEmail, Error> {
+        let mut transaction = self.begin().await?;
+
+        let email = sqlx::query_as!(
+            QueuedEmail,
+            "UPDATE email_queue SET next_attempt = now(), attempts = 0 WHERE id = $1 AND sent IS NULL RETURNING *",
+            id
+        )
+        .fetch_optional(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error retrying email {x}");
+            Error::InternalError
+        })?
+        .ok_or(Error::NoSuchEmail(id))?;
+
+        record(
+            &mut transaction,
+            actor,
+            "retry_email",
+            &id.to_string(),
+            json!({}),
+        )
+        .await?;
+        commit(transaction).await?;
+
+        Ok(email)
+    }
+
+    /// Stops or starts emails about the user's team. Stopping them drops any still waiting to be
+    /// sent.
+    pub async fn set_email_opt_out(&self, user: AuthUser, opt_out: bool) -> Result<User, Error> {
+        // Makes sure they have a row to update
+        self.get_user(user.clone()).await?;
+
+        let mut transaction = self.begin().await?;
+
+        let updated = sqlx::query_as!(
+            User,
+            "UPDATE users SET email_opt_out = $2 WHERE id = $1 RETURNING *",
+            user.id,
+            opt_out
+        )
+        .fetch_one(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error setting email opt out {x}");
+            Error::InternalError
+        })?;
+
+        if opt_out {
+            sqlx::query!(
+                r#"DELETE FROM email_queue WHERE "user" = $1 AND sent IS NULL"#,
+                user.id
+            )
+            .execute(&mut transaction)
+            .await
+            .map_err(|x| {
+                error!("Error removing queued emails {x}");
+                Error::InternalError
+            })?;
+        }
+
+        commit(transaction).await?;
+
+        Ok(updated)
+    }
+
+    /// Reminds teams about deadlines closing within `before`, once for each time the deadline is
+    /// set to. Only teams which haven't submitted a film are reminded to.
+    pub async fn queue_deadline_reminders(&self, before: Duration) -> Result<(), Error> {
+        let mut transaction = self.begin().await?;
+
+        let due = sqlx::query!(
+            r#"WITH deadlines AS (
+    SELECT t.id, t."name", 'submission' AS deadline, coalesce(e.submission_deadline, s.submission_deadline) AS "at"
+    FROM teams t CROSS JOIN festival_schedule s LEFT JOIN deadline_extensions e ON e.team = t.id
+    WHERE t.archived IS NULL AND NOT t.submitted
+    UNION ALL
+    SELECT t.id, t."name", 'edits', coalesce(e.edits_lock, s.edits_lock)
+    FROM teams t CROSS JOIN festival_schedule s LEFT JOIN deadline_extensions e ON e.team = t.id
+    WHERE t.archived IS NULL
+), reminded AS (
+    INSERT INTO deadline_reminders (team, deadline, "at")
+    SELECT id, deadline, "at" FROM deadlines WHERE "at" > now() AND "at" <= now() + make_interval(secs => $1)
+    ON CONFLICT DO NOTHING
+    RETURNING team, deadline, "at"
+)
+SELECT r.team AS "team!", d."name" AS "name!", r.deadline AS "deadline!", r."at" AS "at!"
+FROM reminded r JOIN deadlines d ON d.id = r.team AND d.deadline = r.deadline"#,
+            before.as_secs_f64()
+        )
+        .fetch_all(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error finding deadlines to remind teams about {x}");
+            Error::InternalError
+        })?;
+
+        for reminder in due {
+            // The query only gives these two
+            let deadline = match reminder.deadline.as_str() {
+                "submission" => Deadline::Submission,
+                _ => Deadline::Edits,
+            };
+
+            queue_team_email(
+                &mut transaction,
+                &reminder.team,
+                None,
+                &Email::DeadlineReminder {
+                    team_name: reminder.name,
+                    deadline,
+                    at: reminder.at,
+                },
+            )
+            .await?;
+        }
+
+        commit(transaction).await
+    }
+
+    /// Lets everyone in the team know how they did
+    pub async fn send_judging_results(
+        &self,
+        actor: &str,
+        team_id: String,
+        result: String,
+    ) -> Result<(), Error> {
+        let mut transaction = self.begin().await?;
+        let team = lock_team(&mut transaction, &team_id).await?;
+
+        queue_team_email(
+            &mut transaction,
+            &team_id,
+            None,
+            &Email::JudgingResults {
+                team_name: team.name,
+                result: result.clone(),
+            },
+        )
+        .await?;
+
+        record(
+            &mut transaction,
+            actor,
+            "send_judging_results",
+            &team_id,
+            json!({ "result": result }),
+        )
+        .await?;
+        commit(transaction).await
+    }
+}
@@ -0,0 +1,432 @@
+use super::{emails::queue_team_email, Db, Deadline, Permission, Team};
+use crate::{auth::User as AuthUser, error::*, films::FilmInfo, mail::Email};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::error;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FilmUpload {
+    pub team: String,
+    pub file_name: String,
+    pub size: i64,
+    pub checksum: String,
+    pub received: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Film {
+    pub team: String,
+    pub file_name: String,
+    pub size: i64,
+    pub checksum: String,
+    pub container: Option<String>,
+    pub duration: Option<f64>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub video_codec: Option<String>,
+    pub frame_rate: Option<f64>,
+    /// Longer than the festival allows, and needs looking at by the committee
+    pub overlength: bool,
+    /// Where the film is kept in storage
+    #[serde(skip)]
+    pub storage_key: String,
+}
+
+/// How long a chunk upload can hold the team's upload for, in case the server stops part way
+const UPLOAD_LEASE_SECS: f64 = 15.0 * 60.0;
+
+/// How long before the lease runs out a chunk has to stop being written, to allow for the time
+/// taken to get the lease from the database
+const UPLOAD_LEASE_MARGIN: Duration = Duration::from_secs(60);
+
+/// Holds a team's upload while a chunk is written, so two chunks can't be written at once.
+/// Dropping it without calling one of its methods lets the next chunk through.
+pub struct UploadLock {
+    connection: PgPool,
+    /// Taken once the lease has been used up
+    lease: Option<i64>,
+    /// When writing to the upload has to stop, as someone else could take it after that
+    pub deadline: Instant,
+    pub upload: FilmUpload,
+}
+
+impl Db {
+    pub async fn get_film(&self, user: AuthUser, team_code: String) -> Result<Film, Error> {
+        self.in_specific_team(user, team_code.clone(), Permission::ViewTeams)
+            .await?;
+
+        sqlx::query_as!(
+            Film,
+            r#"SELECT team, file_name, "size", checksum, container, duration, width, height, video_codec, frame_rate, overlength,
+    coalesce(storage_key, team) AS "storage_key!"
+FROM films WHERE team = $1"#,
+            team_code
+        )
+        .fetch_optional(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching film {x}");
+            Error::InternalError
+        })?
+        .ok_or(Error::NoFilm(team_code))
+    }
+
+    /// Sets the name and description of a team's film. Submitting marks the entry as final, after
+    /// which the film needs to keep a name.
+    pub async fn update_film_details(
+        &self,
+        user: AuthUser,
+        team_code: String,
+        film_name: String,
+        film_description: String,
+        submit: bool,
+    ) -> Result<Team, Error> {
+        self.in_specific_team(user, team_code.clone(), Permission::EditTeams)
+            .await?;
+        self.check_deadline(&team_code, Deadline::Edits).await?;
+
+        let team = sqlx::query_as!(Team, "SELECT * FROM teams WHERE id = $1", team_code)
+            .fetch_optional(&self.connection)
+            .await
+            .map_err(|x| {
+                error!("Error fetching team {x}");
+                Error::InternalError
+            })?
+            .ok_or_else(|| Error::NoSuchTeam(team_code.clone()))?;
+
+        if film_name.is_empty() && (submit || team.submitted) {
+            return Err(Error::InvalidFilmDetails(
+                "your film needs a name before it can be submitted".into(),
+            ));
+        }
+        if submit && team.category.is_none() && !self.get_categories().await?.is_empty() {
+            return Err(Error::InvalidFilmDetails(
+                "pick a category before submitting your film".into(),
+            ));
+        }
+
+        sqlx::query_as!(
+            Team,
+            "UPDATE teams SET film_name = $2, film_description = $3, submitted = submitted OR $4 WHERE id = $1 RETURNING *",
+            team_code,
+            film_name,
+            film_description,
+            submit
+        )
+        .fetch_one(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error updating film details {x}");
+            Error::InternalError
+        })
+    }
+
+    pub async fn get_film_upload(
+        &self,
+        user: AuthUser,
+        team_code: String,
+    ) -> Result<Option<FilmUpload>, Error> {
+        self.in_specific_team(user, team_code.clone(), Permission::ViewTeams)
+            .await?;
+
+        sqlx::query_as!(
+            FilmUpload,
+            r#"SELECT team, file_name, "size", checksum, received FROM film_uploads WHERE team = $1"#,
+            team_code
+        )
+        .fetch_optional(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching film upload {x}");
+            Error::InternalError
+        })
+    }
+
+    /// Starts uploading a film for a team. If there is already an upload for the same file it is
+    /// returned as is so the client can resume it, otherwise the progress is reset to zero.
+    pub async fn start_film_upload(
+        &self,
+        user: AuthUser,
+        team_code: String,
+        file_name: String,
+        size: i64,
+        checksum: String,
+    ) -> Result<FilmUpload, Error> {
+        self.in_specific_team(user, team_code.clone(), Permission::EditTeams)
+            .await?;
+        self.check_deadline(&team_code, Deadline::Submission)
+            .await?;
+
+        sqlx::query_as!(
+            FilmUpload,
+            r#"INSERT INTO film_uploads (team, file_name, "size", checksum) VALUES ($1, $2, $3, $4)
+ON CONFLICT (team) DO UPDATE SET
+    lease = CASE WHEN film_uploads."size" = EXCLUDED."size" AND film_uploads.checksum = EXCLUDED.checksum
+        THEN film_uploads.lease ELSE film_uploads.lease + 1 END,
+    leased_until = CASE WHEN film_uploads."size" = EXCLUDED."size" AND film_uploads.checksum = EXCLUDED.checksum
+        THEN film_uploads.leased_until ELSE NULL END,
+    file_name = EXCLUDED.file_name,
+    "size" = EXCLUDED."size",
+    checksum = EXCLUDED.checksum,
+    received = CASE WHEN film_uploads."size" = EXCLUDED."size" AND film_uploads.checksum = EXCLUDED.checksum
+        THEN film_uploads.received ELSE 0 END,
+    started_at = CASE WHEN film_uploads."size" = EXCLUDED."size" AND film_uploads.checksum = EXCLUDED.checksum
+        THEN film_uploads.started_at ELSE now() END
+RETURNING team, file_name, "size", checksum, received"#,
+            team_code,
+            file_name,
+            size,
+            checksum
+        )
+        .fetch_one(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error starting film upload {x}");
+            Error::InternalError
+        })
+    }
+
+    /// Takes the team's upload for writing a chunk, failing if another chunk is still being
+    /// written to it. The lease runs out on its own if the server stops part way.
+    pub async fn lock_film_upload(
+        &self,
+        user: AuthUser,
+        team_code: String,
+    ) -> Result<UploadLock, Error> {
+        self.in_specific_team(user, team_code.clone(), Permission::EditTeams)
+            .await?;
+        self.check_deadline(&team_code, Deadline::Submission)
+            .await?;
+
+        let leased = sqlx::query!(
+            r#"UPDATE film_uploads SET lease = lease + 1, leased_until = now() + make_interval(secs => $2)
+WHERE team = $1 AND (leased_until IS NULL OR leased_until <= now())
+RETURNING team, file_name, "size", checksum, received, lease"#,
+            team_code,
+            UPLOAD_LEASE_SECS
+        )
+        .fetch_optional(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error leasing film upload {x}");
+            Error::InternalError
+        })?;
+
+        let Some(leased) = leased else {
+            let exists = sqlx::query_scalar!(
+                r#"SELECT EXISTS(SELECT 1 FROM film_uploads WHERE team = $1) AS "exists!""#,
+                team_code
+            )
+            .fetch_one(&self.connection)
+            .await
+            .map_err(|x| {
+                error!("Error fetching film upload {x}");
+                Error::InternalError
+            })?;
+
+            return Err(if exists {
+                Error::UploadBusy
+            } else {
+                Error::NoUpload
+            });
+        };
+
+        Ok(UploadLock {
+            connection: self.connection.clone(),
+            lease: Some(leased.lease),
+            deadline: Instant::now() + Duration::from_secs_f64(UPLOAD_LEASE_SECS)
+                - UPLOAD_LEASE_MARGIN,
+            upload: FilmUpload {
+                team: leased.team,
+                file_name: leased.file_name,
+                size: leased.size,
+                checksum: leased.checksum,
+                received: leased.received,
+            },
+        })
+    }
+}
+
+impl UploadLock {
+    pub async fn set_received(mut self, received: i64) -> Result<FilmUpload, Error> {
+        let upload = sqlx::query_as!(
+            FilmUpload,
+            r#"UPDATE film_uploads SET received = $3, leased_until = NULL WHERE team = $1 AND lease = $2
+RETURNING team, file_name, "size", checksum, received"#,
+            self.upload.team,
+            self.lease,
+            received
+        )
+        .fetch_optional(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error updating film upload {x}");
+            Error::InternalError
+        })?
+        // The lease ran out and someone else has taken the upload since
+        .ok_or(Error::UploadBusy)?;
+
+        self.lease = None;
+        Ok(upload)
+    }
+
+    /// Records how much has been received while keeping hold of the upload. Once all of it has
+    /// been received, chunks can't change it any more.
+    pub async fn save_received(&mut self, received: i64) -> Result<(), Error> {
+        sqlx::query!(
+            "UPDATE film_uploads SET received = $3 WHERE team = $1 AND lease = $2 RETURNING team",
+            self.upload.team,
+            self.lease,
+            received
+        )
+        .fetch_optional(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error updating film upload {x}");
+            Error::InternalError
+        })?
+        .ok_or(Error::UploadBusy)?;
+
+        self.upload.received = received;
+        Ok(())
+    }
+
+    /// Records the verified film, stored under `key`, against the team and removes the finished
+    /// upload. Returns the key of the film it replaced, which can be removed from storage.
+    pub async fn complete(
+        mut self,
+        key: &str,
+        info: FilmInfo,
+        overlength: bool,
+    ) -> Result<Option<String>, Error> {
+        let FilmUpload {
+            team,
+            file_name,
+            size,
+            checksum,
+            ..
+        } = self.upload.clone();
+
+        let mut transaction = self.connection.begin().await.map_err(|x| {
+            error!("Error starting transaction {x}");
+            Error::InternalError
+        })?;
+
+        let removed = sqlx::query!(
+            "DELETE FROM film_uploads WHERE team = $1 AND lease = $2",
+            team,
+            self.lease
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error removing finished upload {x}");
+            Error::InternalError
+        })?;
+        if removed.rows_affected() == 0 {
+            return Err(Error::UploadBusy);
+        }
+
+        let replaced = sqlx::query_scalar!(
+            r#"SELECT coalesce(storage_key, team) AS "storage_key!" FROM films WHERE team = $1 FOR UPDATE"#,
+            team
+        )
+        .fetch_optional(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error fetching film {x}");
+            Error::InternalError
+        })?;
+
+        sqlx::query!(
+            r#"INSERT INTO films (team, file_name, "size", checksum, container, duration, width, height, video_codec, frame_rate, overlength, storage_key)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+ON CONFLICT (team) DO UPDATE SET
+    file_name = EXCLUDED.file_name,
+    "size" = EXCLUDED."size",
+    checksum = EXCLUDED.checksum,
+    container = EXCLUDED.container,
+    duration = EXCLUDED.duration,
+    width = EXCLUDED.width,
+    height = EXCLUDED.height,
+    video_codec = EXCLUDED.video_codec,
+    frame_rate = EXCLUDED.frame_rate,
+    overlength = EXCLUDED.overlength,
+    storage_key = EXCLUDED.storage_key,
+    uploaded_at = now()"#,
+            team,
+            file_name,
+            size,
+            checksum,
+            info.container,
+            info.duration,
+            info.width,
+            info.height,
+            info.video_codec,
+            info.frame_rate,
+            overlength,
+            key
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error recording film {x}");
+            Error::InternalError
+        })?;
+
+        let team_name = sqlx::query_scalar!(
+            r#"UPDATE teams SET has_file = true WHERE id = $1 RETURNING "name""#,
+            team
+        )
+        .fetch_one(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error marking team as having a film {x}");
+            Error::InternalError
+        })?;
+
+        queue_team_email(
+            &mut transaction,
+            &team,
+            None,
+            &Email::FilmReceived {
+                team_name,
+                file_name,
+            },
+        )
+        .await?;
+
+        transaction.commit().await.map_err(|x| {
+            error!("Error committing film upload {x}");
+            Error::InternalError
+        })?;
+
+        self.lease = None;
+        Ok(replaced)
+    }
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        let Some(lease) = self.lease.take() else {
+            return;
+        };
+
+        let connection = self.connection.clone();
+        let team = self.upload.team.clone();
+        tokio::spawn(async move {
+            if let Err(x) = sqlx::query!(
+                "UPDATE film_uploads SET leased_until = NULL WHERE team = $1 AND lease = $2",
+                team,
+                lease
+            )
+            .execute(&connection)
+            .await
+            {
+                error!("Error releasing film upload {x}");
+            }
+        });
+    }
+}
@@ -0,0 +1,152 @@
+use super::{
+    commit, hash_token,
+    teams::{
+        add_member, check_captain, check_not_archived, check_team_size, fix_captain, lock_team,
+    },
+    Db, Permission,
+};
+use crate::{auth::User as AuthUser, error::*};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+/// How long someone has to accept an invite, in days
+const INVITE_EXPIRY: i64 = 7;
+
+/// An email inviting someone to join a team
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Invite {
+    pub id: i64,
+    pub team: String,
+    pub team_name: String,
+    pub email: String,
+    pub invited_by: String,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+impl Db {
+    /// Lets the captain invite someone to the team, replacing any invite the team already sent
+    /// them. Whoever has `token` can accept it, as long as they log in with the same address.
+    pub async fn create_invite(
+        &self,
+        user: AuthUser,
+        team_id: String,
+        email: String,
+        token: &str,
+    ) -> Result<Invite, Error> {
+        let mut transaction = self.begin().await?;
+        let team = lock_team(&mut transaction, &team_id).await?;
+        check_captain(&team, &user)?;
+        check_not_archived(&team)?;
+
+        let invite = sqlx::query_as!(
+            Invite,
+            r#"WITH invite AS (
+    INSERT INTO invites (token_hash, team, email, invited_by, expires) VALUES ($1, $2, $3, $4, now() + make_interval(days => $5))
+    ON CONFLICT (team, lower(email)) DO UPDATE SET token_hash = EXCLUDED.token_hash, email = EXCLUDED.email, invited_by = EXCLUDED.invited_by, created = now(), expires = EXCLUDED.expires
+    RETURNING *
+)
+SELECT i.id, i.team, t."name" AS team_name, i.email, i.invited_by, i.created, i.expires FROM invite i JOIN teams t ON t.id = i.team"#,
+            hash_token(token),
+            team_id,
+            email,
+            user.id,
+            INVITE_EXPIRY as i32
+        )
+        .fetch_one(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error creating invite {x}");
+            Error::InternalError
+        })?;
+        commit(transaction).await?;
+
+        Ok(invite)
+    }
+
+    /// The invites to the team which haven't been accepted or expired
+    pub async fn get_invites(&self, user: AuthUser, team_id: String) -> Result<Vec<Invite>, Error> {
+        self.in_specific_team(user, team_id.clone(), Permission::ViewTeams)
+            .await?;
+
+        sqlx::query_as!(
+            Invite,
+            r#"SELECT i.id, i.team, t."name" AS team_name, i.email, i.invited_by, i.created, i.expires FROM invites i JOIN teams t ON t.id = i.team
+WHERE i.team = $1 AND i.expires > now() ORDER BY i.created"#,
+            team_id
+        )
+        .fetch_all(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching invites {x}");
+            Error::InternalError
+        })
+    }
+
+    pub async fn revoke_invite(
+        &self,
+        user: AuthUser,
+        team_id: String,
+        id: i64,
+    ) -> Result<(), Error> {
+        let mut transaction = self.begin().await?;
+        let team = lock_team(&mut transaction, &team_id).await?;
+        check_captain(&team, &user)?;
+
+        let result = sqlx::query!(
+            "DELETE FROM invites WHERE id = $1 AND team = $2",
+            id,
+            team_id
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error revoking invite {x}");
+            Error::InternalError
+        })?;
+        if result.rows_affected() == 0 {
+            return Err(Error::NoSuchInvite);
+        }
+
+        commit(transaction).await
+    }
+
+    /// Puts the user in the team they were invited to, if they logged in with the address the
+    /// invite was sent to. The captain already chose them, so they don't need approving.
+    pub async fn accept_invite(&self, user: AuthUser, token: &str) -> Result<(), Error> {
+        self.check_registration_open().await?;
+
+        let mut transaction = self.begin().await?;
+        let invite = sqlx::query!(
+            "DELETE FROM invites WHERE token_hash = $1 AND expires > now() RETURNING team, email",
+            hash_token(token)
+        )
+        .fetch_optional(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error fetching invite {x}");
+            Error::InternalError
+        })?
+        .ok_or(Error::NoSuchInvite)?;
+
+        // Anyone could have been forwarded the link
+        if !invite.email.eq_ignore_ascii_case(&user.email) {
+            return Err(Error::WrongInviteEmail(invite.email));
+        }
+        // Or signed up with the address without owning it
+        if !user.email_verified {
+            return Err(Error::UnverifiedEmail);
+        }
+
+        let team = lock_team(&mut transaction, &invite.team).await?;
+        check_not_archived(&team)?;
+        self.check_members_eligible(&team, [&user.email]).await?;
+        check_team_size(&mut transaction, &invite.team, team.category.as_deref(), 1).await?;
+
+        add_member(&mut transaction, &user.id, &invite.team, &user.id).await?;
+        fix_captain(&mut transaction, &invite.team).await?;
+
+        commit(transaction).await
+    }
+}
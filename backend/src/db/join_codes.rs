@@ -0,0 +1,210 @@
+use super::{
+    commit,
+    teams::{check_captain, lock_team},
+    Db, Permission,
+};
+use crate::{auth::User as AuthUser, error::*};
+use chrono::{DateTime, Utc};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use tracing::error;
+
+/// Left out 0, 1, I and O, which are easily mixed up when a code is read out
+const CODE_ALPHABET: &[u8] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+
+/// How many times to try making a code nobody else has
+const CODE_ATTEMPTS: usize = 5;
+
+/// A random code in the same `XXX-XXX` form as the team ids people used to join with
+fn generate_code() -> String {
+    let mut rng = thread_rng();
+    let mut code = String::with_capacity(7);
+    for i in 0..6 {
+        if i == 3 {
+            code.push('-');
+        }
+        code.push(CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char);
+    }
+
+    code
+}
+
+/// People type codes in by hand
+fn normalise_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JoinCode {
+    pub code: String,
+    pub team: String,
+    pub expires: Option<DateTime<Utc>>,
+    pub single_use: bool,
+    pub created: DateTime<Utc>,
+}
+
+/// Gives the team a new join code, which replaces any it had
+pub(super) async fn new_join_code(
+    connection: &mut PgConnection,
+    team_id: &str,
+    expires: Option<DateTime<Utc>>,
+    single_use: bool,
+) -> Result<JoinCode, Error> {
+    sqlx::query!("DELETE FROM join_codes WHERE team = $1", team_id)
+        .execute(&mut *connection)
+        .await
+        .map_err(|x| {
+            error!("Error removing join code {x}");
+            Error::InternalError
+        })?;
+
+    for _ in 0..CODE_ATTEMPTS {
+        let code = sqlx::query_as!(
+            JoinCode,
+            "INSERT INTO join_codes (code, team, expires, single_use) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING RETURNING *",
+            generate_code(),
+            team_id,
+            expires,
+            single_use
+        )
+        .fetch_optional(&mut *connection)
+        .await
+        .map_err(|x| {
+            error!("Error creating join code {x}");
+            Error::InternalError
+        })?;
+
+        if let Some(code) = code {
+            return Ok(code);
+        }
+    }
+
+    error!("Couldn't find an unused join code");
+    Err(Error::InternalError)
+}
+
+/// Uses up a single use code, failing if someone else got to it first
+pub(super) async fn use_join_code(connection: &mut PgConnection, code: &str) -> Result<(), Error> {
+    let result = sqlx::query!(
+        "DELETE FROM join_codes WHERE code = $1 AND (expires IS NULL OR expires > now())",
+        code
+    )
+    .execute(&mut *connection)
+    .await
+    .map_err(|x| {
+        error!("Error using join code {x}");
+        Error::InternalError
+    })?;
+
+    if result.rows_affected() == 0 {
+        Err(Error::NoSuchTeam(code.to_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Uses up the code someone was let in with after asking to join, if it's single use. The captain
+/// accepting them is what counts, so it doesn't matter if someone else has used it up already.
+pub(super) async fn use_join_code_if_single_use(
+    connection: &mut PgConnection,
+    code: &str,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "DELETE FROM join_codes WHERE code = $1 AND single_use",
+        code
+    )
+    .execute(&mut *connection)
+    .await
+    .map_err(|x| {
+        error!("Error using join code {x}");
+        Error::InternalError
+    })?;
+
+    Ok(())
+}
+
+/// Finds a code which still works
+pub(super) async fn find_join_code(
+    connection: &mut PgConnection,
+    code: &str,
+) -> Result<JoinCode, Error> {
+    let code = normalise_code(code);
+
+    sqlx::query_as!(
+        JoinCode,
+        "SELECT * FROM join_codes WHERE code = $1 AND (expires IS NULL OR expires > now())",
+        code
+    )
+    .fetch_optional(&mut *connection)
+    .await
+    .map_err(|x| {
+        error!("Error fetching join code {x}");
+        Error::InternalError
+    })?
+    .ok_or(Error::NoSuchTeam(code))
+}
+
+impl Db {
+    pub async fn get_join_code(
+        &self,
+        user: AuthUser,
+        team_id: String,
+    ) -> Result<Option<JoinCode>, Error> {
+        self.in_specific_team(user, team_id.clone(), Permission::ViewTeams)
+            .await?;
+
+        sqlx::query_as!(
+            JoinCode,
+            "SELECT * FROM join_codes WHERE team = $1 AND (expires IS NULL OR expires > now())",
+            team_id
+        )
+        .fetch_optional(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching join code {x}");
+            Error::InternalError
+        })
+    }
+
+    /// Lets the captain replace the team's code, e.g. once it has been shared too widely
+    pub async fn regenerate_join_code(
+        &self,
+        user: AuthUser,
+        team_id: String,
+        expires: Option<DateTime<Utc>>,
+        single_use: bool,
+    ) -> Result<JoinCode, Error> {
+        if expires.map(|x| x <= Utc::now()).unwrap_or(false) {
+            return Err(Error::InvalidTeamChange(
+                "join codes have to expire in the future".into(),
+            ));
+        }
+
+        let mut transaction = self.begin().await?;
+        let team = lock_team(&mut transaction, &team_id).await?;
+        check_captain(&team, &user)?;
+
+        let code = new_join_code(&mut transaction, &team_id, expires, single_use).await?;
+        commit(transaction).await?;
+
+        Ok(code)
+    }
+
+    /// Stops anyone else joining the team, until the captain makes a new code
+    pub async fn revoke_join_code(&self, user: AuthUser, team_id: String) -> Result<(), Error> {
+        let mut transaction = self.begin().await?;
+        let team = lock_team(&mut transaction, &team_id).await?;
+        check_captain(&team, &user)?;
+
+        sqlx::query!("DELETE FROM join_codes WHERE team = $1", team_id)
+            .execute(&mut transaction)
+            .await
+            .map_err(|x| {
+                error!("Error revoking join code {x}");
+                Error::InternalError
+            })?;
+
+        commit(transaction).await
+    }
+}
@@ -0,0 +1,187 @@
+use super::{
+    commit,
+    join_codes::use_join_code_if_single_use,
+    teams::{add_member, check_captain, check_team_size, lock_team},
+    Db, Permission, Team,
+};
+use crate::{auth::User as AuthUser, error::*};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+use tracing::error;
+
+/// The team someone is in, or is waiting to be let into
+#[derive(Debug, Serialize)]
+pub struct CurrentTeam {
+    #[serde(flatten)]
+    pub team: Team,
+    /// They've asked to join and the captain hasn't accepted yet
+    pub pending: bool,
+}
+
+/// Someone waiting for the captain to let them into the team
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JoinRequest {
+    pub user: String,
+    pub name: String,
+    pub email: String,
+    pub created: DateTime<Utc>,
+}
+
+/// Asks the team's captain to let the user in, replacing any request they'd made to another team.
+/// The code they used is kept so it can be used up once they're let in.
+pub(super) async fn request_to_join(
+    transaction: &mut Transaction<'static, Postgres>,
+    team_id: &str,
+    user_id: &str,
+    code: &str,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r#"INSERT INTO join_requests ("user", team, code) VALUES ($1, $2, $3)
+ON CONFLICT ("user") DO UPDATE SET team = EXCLUDED.team, code = EXCLUDED.code, created = now()"#,
+        user_id,
+        team_id,
+        code
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|x| {
+        error!("Error requesting to join team {x}");
+        Error::InternalError
+    })?;
+
+    Ok(())
+}
+
+impl Db {
+    pub async fn get_join_requests(
+        &self,
+        user: AuthUser,
+        team_id: String,
+    ) -> Result<Vec<JoinRequest>, Error> {
+        self.in_specific_team(user, team_id.clone(), Permission::ViewTeams)
+            .await?;
+
+        sqlx::query_as!(
+            JoinRequest,
+            r#"SELECT u.id AS "user", u."name", u.email, r.created FROM join_requests r JOIN users u ON u.id = r."user"
+WHERE r.team = $1 ORDER BY r.created"#,
+            team_id
+        )
+        .fetch_all(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching join requests {x}");
+            Error::InternalError
+        })
+    }
+
+    /// Lets someone who asked to join into the team, as long as they still could join it
+    pub async fn accept_join_request(
+        &self,
+        user: AuthUser,
+        team_id: String,
+        requester: String,
+    ) -> Result<(), Error> {
+        self.check_registration_open().await?;
+
+        let mut transaction = self.begin().await?;
+        let team = lock_team(&mut transaction, &team_id).await?;
+        check_captain(&team, &user)?;
+
+        let request = sqlx::query!(
+            r#"SELECT u.email, r.code FROM join_requests r JOIN users u ON u.id = r."user" WHERE r."user" = $1 AND r.team = $2"#,
+            requester,
+            team_id
+        )
+        .fetch_optional(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error fetching join request {x}");
+            Error::InternalError
+        })?
+        .ok_or_else(|| Error::NoJoinRequest(requester.clone()))?;
+
+        self.check_members_eligible(&team, [&request.email]).await?;
+        check_team_size(&mut transaction, &team_id, team.category.as_deref(), 1).await?;
+        add_member(&mut transaction, &user.id, &team_id, &requester).await?;
+        if let Some(code) = request.code {
+            use_join_code_if_single_use(&mut transaction, &code).await?;
+        }
+
+        commit(transaction).await
+    }
+
+    pub async fn decline_join_request(
+        &self,
+        user: AuthUser,
+        team_id: String,
+        requester: String,
+    ) -> Result<(), Error> {
+        let mut transaction = self.begin().await?;
+        let team = lock_team(&mut transaction, &team_id).await?;
+        check_captain(&team, &user)?;
+
+        let result = sqlx::query!(
+            r#"DELETE FROM join_requests WHERE "user" = $1 AND team = $2"#,
+            requester,
+            team_id
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error declining join request {x}");
+            Error::InternalError
+        })?;
+        if result.rows_affected() == 0 {
+            return Err(Error::NoJoinRequest(requester));
+        }
+
+        commit(transaction).await
+    }
+
+    /// Takes back the user's request to join a team
+    pub async fn cancel_join_request(&self, user: AuthUser) -> Result<(), Error> {
+        let result = sqlx::query!(r#"DELETE FROM join_requests WHERE "user" = $1"#, user.id)
+            .execute(&self.connection)
+            .await
+            .map_err(|x| {
+                error!("Error cancelling join request {x}");
+                Error::InternalError
+            })?;
+
+        if result.rows_affected() == 0 {
+            Err(Error::NoJoinRequest(user.id))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Lets the captain choose whether people joining with the code have to be accepted first
+    pub async fn set_approval_required(
+        &self,
+        user: AuthUser,
+        team_id: String,
+        required: bool,
+    ) -> Result<Team, Error> {
+        let mut transaction = self.begin().await?;
+        let team = lock_team(&mut transaction, &team_id).await?;
+        check_captain(&team, &user)?;
+
+        let team = sqlx::query_as!(
+            Team,
+            "UPDATE teams SET approval_required = $2 WHERE id = $1 RETURNING *",
+            team_id,
+            required
+        )
+        .fetch_one(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error setting whether approval is required {x}");
+            Error::InternalError
+        })?;
+        commit(transaction).await?;
+
+        Ok(team)
+    }
+}
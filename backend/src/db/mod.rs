@@ -1,15 +1,49 @@
-use crate::{auth::User as AuthUser, error::*};
+use crate::{auth::User as AuthUser, error::*, mail::Email};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{postgres::PgPoolOptions, PgPool};
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::PgPoolOptions, PgPool, Postgres, Transaction};
 use std::{borrow::Cow, time::Duration};
 use tracing::{debug, error};
 
+mod archive;
+mod audit;
+mod categories;
+mod emails;
+mod films;
+mod invites;
+mod join_codes;
+mod join_requests;
+mod roles;
+mod schedule;
+mod sessions;
+mod teams;
+mod users;
+
+use emails::queue_email;
+use join_codes::{find_join_code, new_join_code, use_join_code};
+use join_requests::request_to_join;
+use teams::{
+    add_member, check_team_size, fix_captain, lock_team, record_membership, update_emptied,
+};
+
+pub use archive::keep_archiving;
+pub use categories::Category;
+pub use emails::QueuedEmail;
+pub use films::FilmUpload;
+pub use join_requests::CurrentTeam;
+pub use roles::{Permission, Role};
+pub use schedule::{Deadline, DeadlineExtension, Schedule};
+pub use sessions::SessionRefresh;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     pub name: String,
     pub id: String,
     pub email: String,
-    pub is_admin: bool,
+    pub disabled: bool,
+    /// They don't want emails about their team
+    pub email_opt_out: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -19,6 +53,14 @@ pub struct Team {
     film_name: String,
     film_description: String,
     has_file: bool,
+    submitted: bool,
+    category: Option<String>,
+    captain: Option<String>,
+    /// When the last member left, if nobody has joined since
+    emptied: Option<DateTime<Utc>>,
+    archived: Option<DateTime<Utc>>,
+    /// The captain has to accept people joining with the code
+    approval_required: bool,
 }
 
 pub struct Db {
@@ -39,7 +81,10 @@ impl Db {
                 Error::InternalError
             })?;
 
-        Ok(if res.is_none() {
+        Ok(if let Some(res) = res {
+            debug!("Trolling complete, return to hq");
+            res
+        } else {
             sqlx::query_as!(
                 User,
                 "INSERT INTO users (id, \"name\", email) VALUES ($1, $2, $3) RETURNING *",
@@ -53,31 +98,48 @@ impl Db {
                 error!("Error inserting user {ex}");
                 Error::InternalError
             })?
-        } else {
-            debug!("Trolling complete, return to hq");
-            res.unwrap()
         })
     }
 
-    pub async fn is_user_admin(&self, user: &AuthUser) -> Result<bool, Error> {
-        Ok(
-            sqlx::query!("SELECT is_admin FROM users WHERE id=$1", user.id)
-                .fetch_one(&self.connection)
-                .await
-                .map_err(|x| {
-                    error!("Error inserting user {x}");
-                    Error::InternalError
-                })?
-                .is_admin,
-        )
+    async fn begin(&self) -> Result<Transaction<'static, Postgres>, Error> {
+        self.connection.begin().await.map_err(|x| {
+            error!("Error starting transaction {x}");
+            Error::InternalError
+        })
     }
 
-    pub async fn get_team(&self, user: AuthUser) -> Result<Option<Team>, Error> {
-        sqlx::query_as!(
+    /// The team the user is in, or else the one they've asked to join
+    pub async fn get_team(&self, user: AuthUser) -> Result<Option<CurrentTeam>, Error> {
+        let team = sqlx::query_as!(
             Team,
             r#"SELECT t.* FROM user_connection join teams t on t.id = user_connection.team where user_connection."user" = $1;"#,
             user.id,
-        ).fetch_optional(&self.connection).await.map_err(|ex| {error!("Error fetching team: {ex}"); Error::InternalError})
+        ).fetch_optional(&self.connection).await.map_err(|ex| {error!("Error fetching team: {ex}"); Error::InternalError})?;
+
+        if let Some(team) = team {
+            return Ok(Some(CurrentTeam {
+                team,
+                pending: false,
+            }));
+        }
+
+        sqlx::query_as!(
+            Team,
+            r#"SELECT t.* FROM join_requests r JOIN teams t ON t.id = r.team WHERE r."user" = $1"#,
+            user.id
+        )
+        .fetch_optional(&self.connection)
+        .await
+        .map(|x| {
+            x.map(|team| CurrentTeam {
+                team,
+                pending: true,
+            })
+        })
+        .map_err(|x| {
+            error!("Error fetching join request {x}");
+            Error::InternalError
+        })
     }
 
     pub async fn in_team(&self, user: AuthUser) -> Result<bool, Error> {
@@ -93,46 +155,104 @@ impl Db {
             .unwrap())
     }
 
-    pub async fn join_team(&self, user: AuthUser, team_id: String) -> Result<Team, Error> {
+    /// Puts the user in the team with the code, or asks its captain to let them in if the team
+    /// needs them to
+    pub async fn join_team(&self, user: AuthUser, code: String) -> Result<CurrentTeam, Error> {
+        self.check_registration_open().await?;
+
         if self.in_team(user.clone()).await? {
             return Err(Error::InTeam);
         }
 
-        let team = sqlx::query_as!(Team, "SELECT * FROM teams WHERE id = $1", team_id)
-            .fetch_one(&self.connection)
+        let mut transaction = self.begin().await?;
+        let team_id = find_join_code(&mut transaction, &code).await?.team;
+        let mut team = lock_team(&mut transaction, &team_id).await?;
+        // The captain could have revoked or replaced the code before the team was locked
+        let join_code = find_join_code(&mut transaction, &code).await?;
+        if join_code.team != team.id {
+            return Err(Error::NoSuchTeam(join_code.code));
+        }
+        // It was archived after the code was looked up
+        if team.archived.is_some() {
+            return Err(Error::NoSuchTeam(join_code.code));
+        }
+
+        if let Some(category) = team.category.clone() {
+            self.get_category(category)
+                .await?
+                .check_eligible(&user.email)?;
+        }
+        check_team_size(&mut transaction, &team.id, team.category.as_deref(), 1).await?;
+
+        // Nobody can approve anyone for a team without a captain, so they just join
+        if team.approval_required && team.captain.is_some() {
+            // A single use code is only used up once the captain lets them in
+            request_to_join(&mut transaction, &team.id, &user.id, &join_code.code).await?;
+            commit(transaction).await?;
+
+            return Ok(CurrentTeam {
+                team,
+                pending: true,
+            });
+        }
+
+        add_member(&mut transaction, &user.id, &team.id, &user.id).await?;
+        if join_code.single_use {
+            use_join_code(&mut transaction, &join_code.code).await?;
+        }
+
+        // Everyone else left, so the team is theirs now
+        if team.captain.is_none() {
+            team = sqlx::query_as!(
+                Team,
+                "UPDATE teams SET captain = $2 WHERE id = $1 RETURNING *",
+                team.id,
+                user.id
+            )
+            .fetch_one(&mut transaction)
             .await
-            .map_err(|x| match x {
-                sqlx::Error::RowNotFound => Error::NoSuchTeam(team_id),
-                _ => {
-                    error!("{x}");
-                    Error::InternalError
-                }
+            .map_err(|x| {
+                error!("Error making user captain {x}");
+                Error::InternalError
             })?;
+        }
 
-        sqlx::query!(
-            "INSERT INTO user_connection (team, \"user\") VALUES ($1, $2)",
-            team.id,
-            user.id
-        )
-        .execute(&self.connection)
-        .await
-        .map_err(|x| {
-            error!("{x}");
-            Error::InternalError
-        })?;
+        commit(transaction).await?;
 
-        Ok(team)
+        Ok(CurrentTeam {
+            team,
+            pending: false,
+        })
     }
 
-    pub async fn create_team(&self, user: AuthUser, team_name: String) -> Result<Team, Error> {
-        self.in_team(user.clone()).await?;
+    pub async fn create_team(
+        &self,
+        user: AuthUser,
+        team_name: String,
+        category: Option<String>,
+    ) -> Result<Team, Error> {
+        self.check_registration_open().await?;
+
+        if self.in_team(user.clone()).await? {
+            return Err(Error::InTeam);
+        }
+
+        if let Some(category) = category.clone() {
+            self.get_category(category)
+                .await?
+                .check_eligible(&user.email)?;
+        }
+
+        let mut transaction = self.begin().await?;
 
         let team = sqlx::query_as!(
             Team,
-            "INSERT INTO teams (\"name\") VALUES ($1) RETURNING *",
-            team_name
+            "INSERT INTO teams (\"name\", category, captain) VALUES ($1, $2, $3) RETURNING *",
+            team_name,
+            category,
+            user.id
         )
-        .fetch_one(&self.connection)
+        .fetch_one(&mut transaction)
         .await
         .map_err(|x| match x {
             sqlx::Error::Database(ex) => {
@@ -149,27 +269,30 @@ impl Db {
             }
         })?;
 
-        sqlx::query!(
-            "INSERT INTO user_connection (\"user\", team) VALUES ($2, $1)",
-            team.id,
-            user.id
+        add_member(&mut transaction, &user.id, &team.id, &user.id).await?;
+        let code = new_join_code(&mut transaction, &team.id, None, false).await?;
+        queue_email(
+            &mut transaction,
+            &user.id,
+            &Email::TeamCreated {
+                team_name: team.name.clone(),
+                code: code.code,
+            },
         )
-        .execute(&self.connection)
-        .await
-        .map_err(|x| {
-            error!("Couldn't add user to team after creation {x}");
-            Error::InternalError
-        })?;
+        .await?;
+        commit(transaction).await?;
 
         Ok(team)
     }
 
-    pub async fn in_specific_team(&self, user: AuthUser, team_code: String) -> Result<(), Error> {
-        if self
-            .get_user(user.clone())
-            .await
-            .map(|x| x.is_admin)
-            .unwrap_or(false)
+    /// Checks the user is in the team, or has a permission which lets them see or change any team
+    pub async fn in_specific_team(
+        &self,
+        user: AuthUser,
+        team_code: String,
+        bypass: Permission,
+    ) -> Result<(), Error> {
+        if self.has_permission(&user, bypass).await?
             || sqlx::query!(
                 "SELECT exists(SELECT 1 FROM user_connection WHERE \"user\" = $1 AND team = $2)",
                 user.id,
@@ -195,7 +318,8 @@ impl Db {
         user: AuthUser,
         team_code: String,
     ) -> Result<Vec<User>, Error> {
-        self.in_specific_team(user, team_code.clone()).await?;
+        self.in_specific_team(user, team_code.clone(), Permission::ViewTeams)
+            .await?;
 
         sqlx::query_as!(User, "SELECT u.* FROM user_connection JOIN users u on user_connection.\"user\" = u.id WHERE team = $1;", team_code).fetch_all(&self.connection).await.or_else(|x| {
             match x {
@@ -205,33 +329,72 @@ impl Db {
         })
     }
 
+    /// Takes the user out of their team. If they were the captain, whoever has been in the team
+    /// the longest takes over.
     pub async fn leave_team(&self, user: AuthUser) -> Result<(), Error> {
-        if !self.in_team(user.clone()).await? {
-            return Err(Error::NotInTeam);
-        }
+        let mut transaction = self.begin().await?;
+
+        let team = sqlx::query_scalar!(
+            "SELECT team FROM user_connection WHERE \"user\" = $1",
+            user.id
+        )
+        .fetch_optional(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error fetching team {x}");
+            Error::InternalError
+        })?
+        .ok_or(Error::NotInTeam)?;
+        lock_team(&mut transaction, &team).await?;
 
         sqlx::query!("DELETE FROM user_connection WHERE \"user\" = $1", user.id)
-            .execute(&self.connection)
+            .execute(&mut transaction)
             .await
             .map_err(|x| {
                 error!("Error removing user connection {x}");
                 Error::InternalError
             })?;
+        record_membership(&mut transaction, &user.id, &team, &user.id, false).await?;
+        fix_captain(&mut transaction, &team).await?;
+        update_emptied(&mut transaction, &team).await?;
 
-        Ok(())
+        commit(transaction).await
     }
 
-    pub async fn get_teams(&self) -> Result<Vec<Team>, Error> {
-        sqlx::query_as!(Team, "SELECT * FROM teams")
-            .fetch_all(&self.connection)
-            .await
-            .or_else(|x| match x {
-                sqlx::Error::RowNotFound => Result::<Vec<Team>, Error>::Ok(vec![]),
-                _ => Err(Error::InternalError),
-            })
+    /// Lists the teams in use, or the ones which have been archived
+    pub async fn get_teams(
+        &self,
+        category: Option<String>,
+        archived: bool,
+    ) -> Result<Vec<Team>, Error> {
+        sqlx::query_as!(
+            Team,
+            "SELECT * FROM teams WHERE ($1::TEXT IS NULL OR category = $1) AND (archived IS NOT NULL) = $2",
+            category,
+            archived
+        )
+        .fetch_all(&self.connection)
+        .await
+        .or_else(|x| match x {
+            sqlx::Error::RowNotFound => Result::<Vec<Team>, Error>::Ok(vec![]),
+            _ => Err(Error::InternalError),
+        })
     }
 }
 
+/// Sessions and invites are looked up by the hash of their token, so the tables can't be used in
+/// place of the cookie or link
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+async fn commit(transaction: Transaction<'static, Postgres>) -> Result<(), Error> {
+    transaction.commit().await.map_err(|x| {
+        error!("Error committing transaction {x}");
+        Error::InternalError
+    })
+}
+
 pub async fn create_connection() -> Result<PgPool, Error> {
     let url = std::env::var("DATABASE_URL").to_crate("DATABASE_URL")?;
 
@@ -0,0 +1,281 @@
+use super::{audit::record, commit, Db};
+use crate::{auth::User as AuthUser, error::*};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{
+    postgres::{PgHasArrayType, PgTypeInfo},
+    Postgres, Transaction,
+};
+use std::borrow::Cow;
+use tracing::error;
+
+/// Something only some of the committee can do, roles are made up of these
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "permission", rename_all = "snake_case")]
+pub enum Permission {
+    /// See every team, its members and its film
+    ViewTeams,
+    /// Change any team's category, film details and deadline extensions
+    EditTeams,
+    /// Change the schedule and categories
+    ManageFestival,
+    /// Assign roles and log people out
+    ManageUsers,
+}
+
+impl PgHasArrayType for Permission {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_permission")
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Role {
+    pub id: String,
+    pub name: String,
+    pub permissions: Vec<Permission>,
+}
+
+/// Fails if nobody would be left who can give out roles, so the committee can't lock itself out
+pub(super) async fn check_user_managers(
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<(), Error> {
+    let exists = sqlx::query_scalar!(
+        r#"SELECT exists(SELECT 1 FROM user_roles JOIN roles r ON r.id = user_roles."role" JOIN users u ON u.id = user_roles."user"
+WHERE 'manage_users' = ANY(r.permissions) AND NOT u.disabled) AS "exists!""#
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(|x| {
+        error!("Error checking for user managers {x}");
+        Error::InternalError
+    })?;
+
+    if exists {
+        Ok(())
+    } else {
+        Err(Error::WouldLockOut)
+    }
+}
+
+impl Db {
+    pub async fn get_permissions(&self, user: &AuthUser) -> Result<Vec<Permission>, Error> {
+        sqlx::query_scalar!(
+            r#"SELECT DISTINCT unnest(r.permissions) AS "permission!: Permission" FROM user_roles JOIN roles r ON r.id = user_roles."role" WHERE user_roles."user" = $1 ORDER BY 1"#,
+            user.id
+        )
+        .fetch_all(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching permissions {x}");
+            Error::InternalError
+        })
+    }
+
+    pub async fn has_permission(
+        &self,
+        user: &AuthUser,
+        permission: Permission,
+    ) -> Result<bool, Error> {
+        Ok(self.get_permissions(user).await?.contains(&permission))
+    }
+
+    pub async fn get_roles(&self) -> Result<Vec<Role>, Error> {
+        sqlx::query_as!(
+            Role,
+            r#"SELECT id, "name", permissions AS "permissions: Vec<Permission>" FROM roles ORDER BY id"#
+        )
+        .fetch_all(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching roles {x}");
+            Error::InternalError
+        })
+    }
+
+    /// Creates a role, or replaces the one with the same id
+    pub async fn set_role(&self, actor: &str, role: Role) -> Result<Role, Error> {
+        let mut transaction = self.begin().await?;
+
+        let role = sqlx::query_as!(
+            Role,
+            r#"INSERT INTO roles (id, "name", permissions) VALUES ($1, $2, $3)
+ON CONFLICT (id) DO UPDATE SET "name" = EXCLUDED."name", permissions = EXCLUDED.permissions
+RETURNING id, "name", permissions AS "permissions: Vec<Permission>""#,
+            role.id,
+            role.name,
+            role.permissions as _
+        )
+        .fetch_one(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error setting role {x}");
+            Error::InternalError
+        })?;
+
+        check_user_managers(&mut transaction).await?;
+        record(
+            &mut transaction,
+            actor,
+            "set_role",
+            &role.id,
+            json!({ "name": role.name, "permissions": role.permissions }),
+        )
+        .await?;
+        commit(transaction).await?;
+
+        Ok(role)
+    }
+
+    pub async fn remove_role(&self, actor: &str, id: String) -> Result<(), Error> {
+        let mut transaction = self.begin().await?;
+
+        let result = sqlx::query!("DELETE FROM roles WHERE id = $1", id)
+            .execute(&mut transaction)
+            .await
+            .map_err(|x| {
+                error!("Error removing role {x}");
+                Error::InternalError
+            })?;
+        if result.rows_affected() == 0 {
+            return Err(Error::NoSuchRole(id));
+        }
+
+        check_user_managers(&mut transaction).await?;
+        record(&mut transaction, actor, "remove_role", &id, json!({})).await?;
+        commit(transaction).await
+    }
+
+    pub async fn get_user_roles(&self, user_id: String) -> Result<Vec<Role>, Error> {
+        sqlx::query_as!(
+            Role,
+            r#"SELECT r.id, r."name", r.permissions AS "permissions: Vec<Permission>" FROM user_roles JOIN roles r ON r.id = user_roles."role" WHERE user_roles."user" = $1 ORDER BY r.id"#,
+            user_id
+        )
+        .fetch_all(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching user's roles {x}");
+            Error::InternalError
+        })
+    }
+
+    pub async fn add_user_role(
+        &self,
+        actor: &str,
+        user_id: String,
+        role: String,
+    ) -> Result<(), Error> {
+        let mut transaction = self.begin().await?;
+
+        let result = sqlx::query!(
+            "INSERT INTO user_roles (\"user\", \"role\") VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            user_id,
+            role
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(|x| match x {
+            // foreign_key_violation, either the user or role doesn't exist
+            sqlx::Error::Database(ex) if ex.code() == Some(Cow::from("23503")) => {
+                if ex.constraint() == Some("user_roles_role_fkey") {
+                    Error::NoSuchRole(role.clone())
+                } else {
+                    Error::NoSuchUser(user_id.clone())
+                }
+            }
+            _ => {
+                error!("Error adding role {x}");
+                Error::InternalError
+            }
+        })?;
+
+        if result.rows_affected() > 0 {
+            record(
+                &mut transaction,
+                actor,
+                "add_user_role",
+                &user_id,
+                json!({ "role": role }),
+            )
+            .await?;
+        }
+        commit(transaction).await
+    }
+
+    pub async fn remove_user_role(
+        &self,
+        actor: &str,
+        user_id: String,
+        role: String,
+    ) -> Result<(), Error> {
+        let mut transaction = self.begin().await?;
+
+        let result = sqlx::query!(
+            "DELETE FROM user_roles WHERE \"user\" = $1 AND \"role\" = $2",
+            user_id,
+            role
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error removing role {x}");
+            Error::InternalError
+        })?;
+
+        if result.rows_affected() > 0 {
+            check_user_managers(&mut transaction).await?;
+            record(
+                &mut transaction,
+                actor,
+                "remove_user_role",
+                &user_id,
+                json!({ "role": role }),
+            )
+            .await?;
+        }
+        commit(transaction).await
+    }
+
+    /// Gives someone the roles, or takes all of them away. Only used by development login, roles
+    /// are assigned by people who can manage users otherwise.
+    pub async fn set_user_roles(&self, user: AuthUser, roles: &[&str]) -> Result<(), Error> {
+        let mut transaction = self.begin().await?;
+
+        sqlx::query!(
+            "INSERT INTO users (id, \"name\", email) VALUES ($1, $2, $3) ON CONFLICT (id) DO NOTHING",
+            user.id,
+            user.name,
+            user.email
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error inserting user {x}");
+            Error::InternalError
+        })?;
+        sqlx::query!("DELETE FROM user_roles WHERE \"user\" = $1", user.id)
+            .execute(&mut transaction)
+            .await
+            .map_err(|x| {
+                error!("Error removing roles {x}");
+                Error::InternalError
+            })?;
+        for role in roles {
+            sqlx::query!(
+                "INSERT INTO user_roles (\"user\", \"role\") VALUES ($1, $2)",
+                user.id,
+                role
+            )
+            .execute(&mut transaction)
+            .await
+            .map_err(|x| {
+                error!("Error adding role {x}");
+                Error::InternalError
+            })?;
+        }
+
+        commit(transaction).await
+    }
+}
@@ -0,0 +1,177 @@
+use super::Db;
+use crate::error::*;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use tracing::error;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Schedule {
+    pub registration_opens: Option<DateTime<Utc>>,
+    pub registration_closes: Option<DateTime<Utc>>,
+    /// Last chance to upload a film
+    pub submission_deadline: Option<DateTime<Utc>>,
+    /// Last chance to change a film's details
+    pub edits_lock: Option<DateTime<Utc>>,
+    /// The most people a team can have, unless its category has its own limit
+    pub max_team_size: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeadlineExtension {
+    pub team: String,
+    pub submission_deadline: Option<DateTime<Utc>>,
+    pub edits_lock: Option<DateTime<Utc>>,
+}
+
+/// The deadlines which can be extended for a team
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Deadline {
+    Submission,
+    Edits,
+}
+
+impl Deadline {
+    /// What can't be done once it has passed
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Deadline::Submission => "submitting films",
+            Deadline::Edits => "changing film details",
+        }
+    }
+}
+
+impl Db {
+    pub async fn get_schedule(&self) -> Result<Schedule, Error> {
+        sqlx::query_as!(
+            Schedule,
+            "SELECT registration_opens, registration_closes, submission_deadline, edits_lock, max_team_size FROM festival_schedule"
+        )
+        .fetch_one(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching schedule {x}");
+            Error::InternalError
+        })
+    }
+
+    pub async fn set_schedule(&self, schedule: Schedule) -> Result<Schedule, Error> {
+        sqlx::query_as!(
+            Schedule,
+            "UPDATE festival_schedule SET registration_opens = $1, registration_closes = $2, submission_deadline = $3, edits_lock = $4, max_team_size = $5
+RETURNING registration_opens, registration_closes, submission_deadline, edits_lock, max_team_size",
+            schedule.registration_opens,
+            schedule.registration_closes,
+            schedule.submission_deadline,
+            schedule.edits_lock,
+            schedule.max_team_size
+        )
+        .fetch_one(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error updating schedule {x}");
+            Error::InternalError
+        })
+    }
+
+    pub async fn get_deadline_extensions(&self) -> Result<Vec<DeadlineExtension>, Error> {
+        sqlx::query_as!(
+            DeadlineExtension,
+            "SELECT team, submission_deadline, edits_lock FROM deadline_extensions"
+        )
+        .fetch_all(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching deadline extensions {x}");
+            Error::InternalError
+        })
+    }
+
+    pub async fn set_deadline_extension(
+        &self,
+        extension: DeadlineExtension,
+    ) -> Result<DeadlineExtension, Error> {
+        sqlx::query_as!(
+            DeadlineExtension,
+            "INSERT INTO deadline_extensions (team, submission_deadline, edits_lock) VALUES ($1, $2, $3)
+ON CONFLICT (team) DO UPDATE SET submission_deadline = EXCLUDED.submission_deadline, edits_lock = EXCLUDED.edits_lock
+RETURNING team, submission_deadline, edits_lock",
+            extension.team,
+            extension.submission_deadline,
+            extension.edits_lock
+        )
+        .fetch_one(&self.connection)
+        .await
+        .map_err(|x| match x {
+            // foreign_key_violation, the team doesn't exist
+            sqlx::Error::Database(ex) if ex.code() == Some(Cow::from("23503")) => {
+                Error::NoSuchTeam(extension.team)
+            }
+            _ => {
+                error!("Error setting deadline extension {x}");
+                Error::InternalError
+            }
+        })
+    }
+
+    pub async fn remove_deadline_extension(&self, team_code: String) -> Result<(), Error> {
+        sqlx::query!("DELETE FROM deadline_extensions WHERE team = $1", team_code)
+            .execute(&self.connection)
+            .await
+            .map_err(|x| {
+                error!("Error removing deadline extension {x}");
+                Error::InternalError
+            })?;
+
+        Ok(())
+    }
+
+    /// Teams can only be founded or joined while registration is open
+    pub async fn check_registration_open(&self) -> Result<(), Error> {
+        let Schedule {
+            registration_opens,
+            registration_closes,
+            ..
+        } = self.get_schedule().await?;
+        let now = Utc::now();
+
+        if registration_opens.map(|x| now < x).unwrap_or(false) {
+            Err(Error::RegistrationNotOpen)
+        } else if registration_closes.map(|x| now >= x).unwrap_or(false) {
+            Err(Error::DeadlinePassed("registration".into()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks a deadline for a team, taking any extension they have been given into account
+    pub async fn check_deadline(&self, team_code: &str, deadline: Deadline) -> Result<(), Error> {
+        let passed = match deadline {
+            Deadline::Submission => sqlx::query_scalar!(
+                r#"SELECT coalesce(e.submission_deadline, s.submission_deadline) <= now() AS "passed"
+FROM festival_schedule s LEFT JOIN deadline_extensions e ON e.team = $1"#,
+                team_code
+            )
+            .fetch_one(&self.connection)
+            .await,
+            Deadline::Edits => sqlx::query_scalar!(
+                r#"SELECT coalesce(e.edits_lock, s.edits_lock) <= now() AS "passed"
+FROM festival_schedule s LEFT JOIN deadline_extensions e ON e.team = $1"#,
+                team_code
+            )
+            .fetch_one(&self.connection)
+            .await,
+        }
+        .map_err(|x| {
+            error!("Error checking deadline {x}");
+            Error::InternalError
+        })?;
+
+        if passed.unwrap_or(false) {
+            Err(Error::DeadlinePassed(deadline.describe().into()))
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use super::{audit::record, commit, hash_token, Db};
+use crate::error::*;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::PgPool;
+use tracing::error;
+
+/// How long a request refreshing a session's tokens has before another can, in seconds. Requests
+/// to the provider time out well before this.
+const REFRESH_LEASE_SECS: f64 = 60.0;
+
+/// A session as admins see it, without any of its tokens
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub id: i64,
+    pub user: String,
+    pub created: DateTime<Utc>,
+    pub last_used: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionTokens {
+    pub id: i64,
+    pub id_token: String,
+    pub id_token_expires: DateTime<Utc>,
+    pub refresh_token: Option<String>,
+}
+
+/// Holds a session while its tokens are refreshed, so a rotated refresh token is only used once.
+/// Dropping it without calling one of its methods lets someone else refresh it.
+pub struct SessionRefresh {
+    connection: PgPool,
+    /// Taken once the lease has been used up
+    lease: Option<i64>,
+    pub tokens: SessionTokens,
+}
+
+impl Db {
+    pub async fn create_session(
+        &self,
+        user_id: String,
+        token: &str,
+        id_token: String,
+        id_token_expires: DateTime<Utc>,
+        refresh_token: Option<String>,
+        expires: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        // Nothing else cleans up sessions which ran out
+        sqlx::query!("DELETE FROM sessions WHERE expires <= now()")
+            .execute(&self.connection)
+            .await
+            .map_err(|x| {
+                error!("Error removing expired sessions {x}");
+                Error::InternalError
+            })?;
+
+        sqlx::query!(
+            r#"INSERT INTO sessions (token_hash, "user", id_token, id_token_expires, refresh_token, expires)
+VALUES ($1, $2, $3, $4, $5, $6)"#,
+            hash_token(token),
+            user_id,
+            id_token,
+            id_token_expires,
+            refresh_token,
+            expires
+        )
+        .execute(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error creating session {x}");
+            Error::InternalError
+        })?;
+
+        Ok(())
+    }
+
+    /// Finds a session which hasn't expired, marking it as used
+    pub async fn use_session(&self, token: &str) -> Result<Option<SessionTokens>, Error> {
+        sqlx::query_as!(
+            SessionTokens,
+            "UPDATE sessions SET last_used = now() WHERE token_hash = $1 AND expires > now()
+RETURNING id, id_token, id_token_expires, refresh_token",
+            hash_token(token)
+        )
+        .fetch_optional(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching session {x}");
+            Error::InternalError
+        })
+    }
+
+    /// Takes a session so its tokens can be refreshed, unless someone else is already refreshing
+    /// it or it has ended
+    pub async fn claim_session_refresh(&self, id: i64) -> Result<Option<SessionRefresh>, Error> {
+        let claimed = sqlx::query!(
+            "UPDATE sessions SET refresh_lease = refresh_lease + 1, refreshing_until = now() + make_interval(secs => $2)
+WHERE id = $1 AND expires > now() AND (refreshing_until IS NULL OR refreshing_until <= now())
+RETURNING id, id_token, id_token_expires, refresh_token, refresh_lease",
+            id,
+            REFRESH_LEASE_SECS
+        )
+        .fetch_optional(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error claiming session {x}");
+            Error::InternalError
+        })?;
+
+        Ok(claimed.map(|claimed| SessionRefresh {
+            connection: self.connection.clone(),
+            lease: Some(claimed.refresh_lease),
+            tokens: SessionTokens {
+                id: claimed.id,
+                id_token: claimed.id_token,
+                id_token_expires: claimed.id_token_expires,
+                refresh_token: claimed.refresh_token,
+            },
+        }))
+    }
+
+    pub async fn get_sessions(&self, user_id: Option<String>) -> Result<Vec<Session>, Error> {
+        sqlx::query_as!(
+            Session,
+            r#"SELECT id, "user", created, last_used, expires FROM sessions
+WHERE expires > now() AND ($1::TEXT IS NULL OR "user" = $1) ORDER BY last_used DESC"#,
+            user_id
+        )
+        .fetch_all(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching sessions {x}");
+            Error::InternalError
+        })
+    }
+
+    /// Ends a session from its cookie, when someone logs out. Returns the session's id token, to
+    /// show the provider who is logging out.
+    pub async fn end_session(&self, token: &str) -> Result<Option<String>, Error> {
+        sqlx::query_scalar!(
+            "DELETE FROM sessions WHERE token_hash = $1 RETURNING id_token",
+            hash_token(token)
+        )
+        .fetch_optional(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error ending session {x}");
+            Error::InternalError
+        })
+    }
+
+    pub async fn revoke_session(&self, actor: &str, id: i64) -> Result<(), Error> {
+        let mut transaction = self.begin().await?;
+
+        let user =
+            sqlx::query_scalar!(r#"DELETE FROM sessions WHERE id = $1 RETURNING "user""#, id)
+                .fetch_optional(&mut transaction)
+                .await
+                .map_err(|x| {
+                    error!("Error revoking session {x}");
+                    Error::InternalError
+                })?;
+
+        if let Some(user) = user {
+            record(
+                &mut transaction,
+                actor,
+                "revoke_session",
+                &user,
+                json!({ "session": id }),
+            )
+            .await?;
+        }
+        commit(transaction).await
+    }
+
+    /// Logs someone out everywhere
+    pub async fn revoke_user_sessions(&self, actor: &str, user_id: String) -> Result<(), Error> {
+        let mut transaction = self.begin().await?;
+
+        sqlx::query!(r#"DELETE FROM sessions WHERE "user" = $1"#, user_id)
+            .execute(&mut transaction)
+            .await
+            .map_err(|x| {
+                error!("Error revoking sessions {x}");
+                Error::InternalError
+            })?;
+
+        record(
+            &mut transaction,
+            actor,
+            "revoke_user_sessions",
+            &user_id,
+            json!({}),
+        )
+        .await?;
+        commit(transaction).await
+    }
+}
+
+impl SessionRefresh {
+    /// Stores refreshed tokens. Fails if the session ended while they were being fetched.
+    pub async fn update(
+        mut self,
+        id_token: String,
+        id_token_expires: DateTime<Utc>,
+        refresh_token: Option<String>,
+    ) -> Result<(), Error> {
+        sqlx::query!(
+            "UPDATE sessions SET id_token = $3, id_token_expires = $4, refresh_token = $5, refreshing_until = NULL
+WHERE id = $1 AND refresh_lease = $2 RETURNING id",
+            self.tokens.id,
+            self.lease,
+            id_token,
+            id_token_expires,
+            refresh_token
+        )
+        .fetch_optional(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error updating session {x}");
+            Error::InternalError
+        })?
+        .ok_or(Error::Unauthorized)?;
+
+        self.lease = None;
+        Ok(())
+    }
+
+    /// Ends the session, when its tokens can't be refreshed
+    pub async fn end(mut self) -> Result<(), Error> {
+        sqlx::query!(
+            "DELETE FROM sessions WHERE id = $1 AND refresh_lease = $2",
+            self.tokens.id,
+            self.lease
+        )
+        .execute(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error ending session {x}");
+            Error::InternalError
+        })?;
+
+        self.lease = None;
+        Ok(())
+    }
+}
+
+impl Drop for SessionRefresh {
+    fn drop(&mut self) {
+        let Some(lease) = self.lease.take() else {
+            return;
+        };
+
+        let connection = self.connection.clone();
+        let id = self.tokens.id;
+        tokio::spawn(async move {
+            if let Err(x) = sqlx::query!(
+                "UPDATE sessions SET refreshing_until = NULL WHERE id = $1 AND refresh_lease = $2",
+                id,
+                lease
+            )
+            .execute(&connection)
+            .await
+            {
+                error!("Error releasing session {x}");
+            }
+        });
+    }
+}
@@ -0,0 +1,549 @@
+use super::{audit::record, commit, emails::queue_team_email, Db, Team};
+use crate::{auth::User as AuthUser, error::*, mail::Email};
+use serde_json::json;
+use sqlx::{Postgres, Transaction};
+use std::borrow::Cow;
+use tracing::error;
+
+/// Locks the teams until the transaction ends, so nobody joins or leaves them in the meantime.
+/// They are always locked in the same order, so two changes can't wait on each other forever.
+pub(super) async fn lock_teams(
+    transaction: &mut Transaction<'static, Postgres>,
+    ids: &[String],
+) -> Result<Vec<Team>, Error> {
+    let teams = sqlx::query_as!(
+        Team,
+        "SELECT * FROM teams WHERE id = ANY($1) ORDER BY id FOR UPDATE",
+        ids
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(|x| {
+        error!("Error locking teams {x}");
+        Error::InternalError
+    })?;
+
+    match ids
+        .iter()
+        .find(|x| !teams.iter().any(|team| &team.id == *x))
+    {
+        Some(missing) => Err(Error::NoSuchTeam(missing.clone())),
+        None => Ok(teams),
+    }
+}
+
+pub(super) async fn lock_team(
+    transaction: &mut Transaction<'static, Postgres>,
+    id: &str,
+) -> Result<Team, Error> {
+    lock_teams(transaction, &[id.to_owned()])
+        .await?
+        .pop()
+        .ok_or_else(|| Error::NoSuchTeam(id.to_owned()))
+}
+
+/// The ids and emails of everyone in the team
+async fn team_members(
+    transaction: &mut Transaction<'static, Postgres>,
+    id: &str,
+) -> Result<Vec<(String, String)>, Error> {
+    sqlx::query!(
+        "SELECT u.id, u.email FROM user_connection JOIN users u ON user_connection.\"user\" = u.id WHERE team = $1 ORDER BY user_connection.id",
+        id
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map(|x| x.into_iter().map(|x| (x.id, x.email)).collect())
+    .map_err(|x| {
+        error!("Error fetching team members {x}");
+        Error::InternalError
+    })
+}
+
+/// Puts the user in the team. Fails if they are already in one, even if they joined it after
+/// this was checked.
+pub(super) async fn add_member(
+    transaction: &mut Transaction<'static, Postgres>,
+    actor: &str,
+    team_id: &str,
+    user_id: &str,
+) -> Result<(), Error> {
+    sqlx::query!(
+        "INSERT INTO user_connection (\"user\", team) VALUES ($1, $2)",
+        user_id,
+        team_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|x| match x {
+        // unique_violation, people can only be in one team
+        sqlx::Error::Database(ex) if ex.code() == Some(Cow::from("23505")) => Error::InTeam,
+        _ => {
+            error!("Error adding user to team {x}");
+            Error::InternalError
+        }
+    })?;
+
+    // Anything else they'd asked to join doesn't matter now
+    sqlx::query!(r#"DELETE FROM join_requests WHERE "user" = $1"#, user_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(|x| {
+            error!("Error removing join request {x}");
+            Error::InternalError
+        })?;
+
+    record_membership(transaction, actor, team_id, user_id, true).await?;
+    update_emptied(transaction, team_id).await
+}
+
+/// Adds to the team's history of who has joined and left it, and lets the rest of the team know
+pub(super) async fn record_membership(
+    transaction: &mut Transaction<'static, Postgres>,
+    actor: &str,
+    team_id: &str,
+    user_id: &str,
+    joined: bool,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r#"INSERT INTO membership_changes (team, "user", joined, actor) VALUES ($1, $2, $3, $4)"#,
+        team_id,
+        user_id,
+        joined,
+        actor
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|x| {
+        error!("Error recording membership change {x}");
+        Error::InternalError
+    })?;
+
+    let names = sqlx::query!(
+        r#"SELECT t."name" AS team_name, u."name" AS member FROM teams t, users u WHERE t.id = $1 AND u.id = $2"#,
+        team_id,
+        user_id
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(|x| {
+        error!("Error fetching names for membership email {x}");
+        Error::InternalError
+    })?;
+
+    let email = if joined {
+        Email::MemberJoined {
+            team_name: names.team_name,
+            member: names.member,
+        }
+    } else {
+        Email::MemberLeft {
+            team_name: names.team_name,
+            member: names.member,
+        }
+    };
+    queue_team_email(transaction, team_id, Some(user_id), &email).await
+}
+
+/// Notes when the team was left empty, so it can be archived once it has been empty for long
+/// enough, or clears it if someone has joined since
+pub(super) async fn update_emptied(
+    transaction: &mut Transaction<'static, Postgres>,
+    id: &str,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r#"UPDATE teams SET emptied = CASE WHEN exists(SELECT 1 FROM user_connection WHERE team = $1) THEN NULL ELSE coalesce(emptied, now()) END
+WHERE id = $1"#,
+        id
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|x| {
+        error!("Error updating when team was emptied {x}");
+        Error::InternalError
+    })?;
+
+    Ok(())
+}
+
+/// Archived teams have to be restored before anyone can be put in them
+pub(super) fn check_not_archived(team: &Team) -> Result<(), Error> {
+    if team.archived.is_some() {
+        Err(Error::InvalidTeamChange(format!(
+            "{} is archived, restore it first",
+            team.id
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Makes whoever has been in the team the longest its captain, if its captain has left
+pub(super) async fn fix_captain(
+    transaction: &mut Transaction<'static, Postgres>,
+    id: &str,
+) -> Result<(), Error> {
+    sqlx::query!(
+        r#"UPDATE teams SET captain = (SELECT "user" FROM user_connection WHERE team = $1 ORDER BY id LIMIT 1)
+WHERE id = $1 AND (captain IS NULL OR NOT exists(SELECT 1 FROM user_connection WHERE team = $1 AND "user" = teams.captain))"#,
+        id
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|x| {
+        error!("Error reassigning captain {x}");
+        Error::InternalError
+    })?;
+
+    Ok(())
+}
+
+/// Fails if `joining` more people would take the team over the size limit for the category. The
+/// team has to be locked, so nobody else can join between counting its members and adding more.
+pub(super) async fn check_team_size(
+    transaction: &mut Transaction<'static, Postgres>,
+    id: &str,
+    category: Option<&str>,
+    joining: i64,
+) -> Result<(), Error> {
+    let size = sqlx::query!(
+        r#"SELECT coalesce(c.max_team_size, s.max_team_size) AS "limit", (SELECT count(*) FROM user_connection WHERE team = $1) AS "members!"
+FROM festival_schedule s LEFT JOIN categories c ON c.id = $2"#,
+        id,
+        category
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(|x| {
+        error!("Error checking team size {x}");
+        Error::InternalError
+    })?;
+
+    match size.limit {
+        Some(limit) if size.members + joining > limit as i64 => {
+            Err(Error::TeamFull(id.to_owned(), limit))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks the user is the team's captain
+pub(super) fn check_captain(team: &Team, user: &AuthUser) -> Result<(), Error> {
+    if team.captain.as_deref() == Some(user.id.as_str()) {
+        Ok(())
+    } else {
+        Err(Error::NotCaptain)
+    }
+}
+
+impl Db {
+    /// Lets the captain take someone out of their team, e.g. if they joined with a leaked code
+    pub async fn remove_member(
+        &self,
+        user: AuthUser,
+        team_id: String,
+        member: String,
+    ) -> Result<(), Error> {
+        let mut transaction = self.begin().await?;
+        let team = lock_team(&mut transaction, &team_id).await?;
+        check_captain(&team, &user)?;
+
+        if member == user.id {
+            return Err(Error::InvalidTeamChange(
+                "captains can't remove themselves, leave the team instead".into(),
+            ));
+        }
+
+        let result = sqlx::query!(
+            "DELETE FROM user_connection WHERE team = $1 AND \"user\" = $2",
+            team_id,
+            member
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error removing team member {x}");
+            Error::InternalError
+        })?;
+        if result.rows_affected() == 0 {
+            return Err(Error::NotTeamMember(member));
+        }
+
+        record_membership(&mut transaction, &user.id, &team_id, &member, false).await?;
+        commit(transaction).await
+    }
+
+    /// Hands the team over to another of its members
+    pub async fn transfer_captaincy(
+        &self,
+        user: AuthUser,
+        team_id: String,
+        captain: String,
+    ) -> Result<Team, Error> {
+        let mut transaction = self.begin().await?;
+        let team = lock_team(&mut transaction, &team_id).await?;
+        check_captain(&team, &user)?;
+
+        let team = sqlx::query_as!(
+            Team,
+            r#"UPDATE teams SET captain = $2 WHERE id = $1 AND exists(SELECT 1 FROM user_connection WHERE team = $1 AND "user" = $2) RETURNING *"#,
+            team_id,
+            captain
+        )
+        .fetch_optional(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error transferring captaincy {x}");
+            Error::InternalError
+        })?
+        .ok_or(Error::NotTeamMember(captain))?;
+
+        commit(transaction).await?;
+
+        Ok(team)
+    }
+
+    /// Checks everyone can be in a team entered in the category, if it has one
+    pub(super) async fn check_members_eligible<'a>(
+        &self,
+        team: &Team,
+        emails: impl IntoIterator<Item = &'a String>,
+    ) -> Result<(), Error> {
+        if let Some(category) = team.category.clone() {
+            let category = self.get_category(category).await?;
+            for email in emails {
+                category.check_eligible(email)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn rename_team(&self, actor: &str, id: String, name: String) -> Result<Team, Error> {
+        let name = name.trim().to_owned();
+        if name.is_empty() {
+            return Err(Error::InvalidTeamChange("team names can't be empty".into()));
+        }
+
+        let mut transaction = self.begin().await?;
+        let old = lock_team(&mut transaction, &id).await?;
+
+        let team = sqlx::query_as!(
+            Team,
+            "UPDATE teams SET \"name\" = $2 WHERE id = $1 RETURNING *",
+            id,
+            name
+        )
+        .fetch_one(&mut transaction)
+        .await
+        .map_err(|x| match x {
+            // unique_violation, the name is taken ignoring case
+            sqlx::Error::Database(ex) if ex.code() == Some(Cow::from("23505")) => {
+                Error::TeamNameTaken(name.clone())
+            }
+            _ => {
+                error!("Error renaming team {x}");
+                Error::InternalError
+            }
+        })?;
+
+        record(
+            &mut transaction,
+            actor,
+            "rename_team",
+            &id,
+            json!({ "from": old.name, "to": team.name }),
+        )
+        .await?;
+        commit(transaction).await?;
+
+        Ok(team)
+    }
+
+    /// Deletes a team along with its deadline extension and film. The film's file has to be
+    /// removed from storage separately, once this has succeeded, so its key is returned as well.
+    pub async fn delete_team(
+        &self,
+        actor: &str,
+        id: String,
+    ) -> Result<(Team, Option<String>), Error> {
+        let mut transaction = self.begin().await?;
+        let team = lock_team(&mut transaction, &id).await?;
+        let members = team_members(&mut transaction, &id).await?;
+
+        let film = sqlx::query_scalar!(
+            r#"DELETE FROM films WHERE team = $1 RETURNING coalesce(storage_key, team) AS "storage_key!""#,
+            id
+        )
+        .fetch_optional(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error deleting team {x}");
+            Error::InternalError
+        })?;
+
+        for query in [
+            sqlx::query!("DELETE FROM film_uploads WHERE team = $1", id),
+            sqlx::query!("DELETE FROM deadline_extensions WHERE team = $1", id),
+            sqlx::query!("DELETE FROM user_connection WHERE team = $1", id),
+            sqlx::query!("DELETE FROM teams WHERE id = $1", id),
+        ] {
+            query.execute(&mut transaction).await.map_err(|x| {
+                error!("Error deleting team {x}");
+                Error::InternalError
+            })?;
+        }
+
+        record(
+            &mut transaction,
+            actor,
+            "delete_team",
+            &id,
+            json!({
+                "name": team.name,
+                "members": members.into_iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            }),
+        )
+        .await?;
+        commit(transaction).await?;
+
+        Ok((team, film))
+    }
+
+    /// Puts someone in a team, taking them out of the one they were in
+    pub async fn move_user(
+        &self,
+        actor: &str,
+        user_id: String,
+        team_id: String,
+    ) -> Result<Team, Error> {
+        let mut transaction = self.begin().await?;
+        let team = lock_team(&mut transaction, &team_id).await?;
+        check_not_archived(&team)?;
+
+        let email = sqlx::query_scalar!("SELECT email FROM users WHERE id = $1", user_id)
+            .fetch_optional(&mut transaction)
+            .await
+            .map_err(|x| {
+                error!("Error fetching user {x}");
+                Error::InternalError
+            })?
+            .ok_or_else(|| Error::NoSuchUser(user_id.clone()))?;
+        self.check_members_eligible(&team, [&email]).await?;
+
+        let from = sqlx::query_scalar!(
+            "DELETE FROM user_connection WHERE \"user\" = $1 RETURNING team",
+            user_id
+        )
+        .fetch_optional(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error removing user connection {x}");
+            Error::InternalError
+        })?;
+        check_team_size(&mut transaction, &team_id, team.category.as_deref(), 1).await?;
+        if let Some(from) = &from {
+            record_membership(&mut transaction, actor, from, &user_id, false).await?;
+            fix_captain(&mut transaction, from).await?;
+            update_emptied(&mut transaction, from).await?;
+        }
+        add_member(&mut transaction, actor, &team_id, &user_id).await?;
+        fix_captain(&mut transaction, &team_id).await?;
+
+        record(
+            &mut transaction,
+            actor,
+            "move_user",
+            &user_id,
+            json!({ "from": from, "to": team_id }),
+        )
+        .await?;
+        commit(transaction).await?;
+
+        Ok(team)
+    }
+
+    /// Moves everyone in `from` into `into`, then deletes `from`. Only `into`'s film and details
+    /// are kept, so `from` can't have a film.
+    pub async fn merge_teams(
+        &self,
+        actor: &str,
+        from: String,
+        into: String,
+    ) -> Result<Team, Error> {
+        if from == into {
+            return Err(Error::InvalidTeamChange(
+                "a team can't be merged into itself".into(),
+            ));
+        }
+
+        let mut transaction = self.begin().await?;
+        let teams = lock_teams(&mut transaction, &[from.clone(), into.clone()]).await?;
+        let team = teams
+            .into_iter()
+            .find(|x| x.id == into)
+            .ok_or_else(|| Error::NoSuchTeam(into.clone()))?;
+        check_not_archived(&team)?;
+
+        let has_film = sqlx::query_scalar!(
+            r#"SELECT exists(SELECT 1 FROM films WHERE team = $1) OR exists(SELECT 1 FROM film_uploads WHERE team = $1) AS "exists!""#,
+            from
+        )
+        .fetch_one(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error checking for film {x}");
+            Error::InternalError
+        })?;
+        if has_film {
+            return Err(Error::TeamHasFilm(from));
+        }
+
+        let members = team_members(&mut transaction, &from).await?;
+        self.check_members_eligible(&team, members.iter().map(|(_, email)| email))
+            .await?;
+        check_team_size(
+            &mut transaction,
+            &into,
+            team.category.as_deref(),
+            members.len() as i64,
+        )
+        .await?;
+
+        for query in [
+            sqlx::query!(
+                "UPDATE user_connection SET team = $2 WHERE team = $1",
+                from,
+                into
+            ),
+            sqlx::query!("DELETE FROM deadline_extensions WHERE team = $1", from),
+            sqlx::query!("DELETE FROM teams WHERE id = $1", from),
+        ] {
+            query.execute(&mut transaction).await.map_err(|x| {
+                error!("Error merging teams {x}");
+                Error::InternalError
+            })?;
+        }
+
+        for (id, _) in &members {
+            record_membership(&mut transaction, actor, &into, id, true).await?;
+        }
+        fix_captain(&mut transaction, &into).await?;
+        update_emptied(&mut transaction, &into).await?;
+
+        record(
+            &mut transaction,
+            actor,
+            "merge_team",
+            &from,
+            json!({
+                "into": into,
+                "members": members.into_iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            }),
+        )
+        .await?;
+        commit(transaction).await?;
+
+        Ok(team)
+    }
+}
@@ -0,0 +1,159 @@
+use super::{
+    audit::record, commit, roles::check_user_managers, sessions::Session, Db, Role, Team, User,
+};
+use crate::error::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::error;
+
+/// A user in the admin list, with the team they're in
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserListing {
+    pub id: String,
+    pub name: String,
+    pub email: String,
+    pub disabled: bool,
+    pub team: Option<String>,
+    pub team_name: Option<String>,
+}
+
+/// Everything admins can see about a user
+#[derive(Debug, Serialize)]
+pub struct UserDetails {
+    #[serde(flatten)]
+    pub user: User,
+    pub team: Option<Team>,
+    pub roles: Vec<Role>,
+    pub sessions: Vec<Session>,
+}
+
+impl Db {
+    /// Lists everyone, or only those whose id, name or email contains `search`
+    pub async fn search_users(&self, search: Option<String>) -> Result<Vec<UserListing>, Error> {
+        sqlx::query_as!(
+            UserListing,
+            r#"SELECT u.id, u."name", u.email, u.disabled, t.id AS "team?", t."name" AS "team_name?" FROM users u
+LEFT JOIN user_connection c ON c."user" = u.id LEFT JOIN teams t ON t.id = c.team
+WHERE $1::TEXT IS NULL OR strpos(lower(u.id), lower($1)) > 0 OR strpos(lower(u."name"), lower($1)) > 0 OR strpos(lower(u.email), lower($1)) > 0
+ORDER BY u."name", u.id"#,
+            search
+        )
+        .fetch_all(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error searching users {x}");
+            Error::InternalError
+        })
+    }
+
+    pub async fn get_user_details(&self, id: String) -> Result<UserDetails, Error> {
+        let user = sqlx::query_as!(User, "SELECT * FROM users WHERE id = $1", id)
+            .fetch_optional(&self.connection)
+            .await
+            .map_err(|x| {
+                error!("Error fetching user {x}");
+                Error::InternalError
+            })?
+            .ok_or_else(|| Error::NoSuchUser(id.clone()))?;
+
+        let team = sqlx::query_as!(
+            Team,
+            r#"SELECT t.* FROM user_connection JOIN teams t ON t.id = user_connection.team WHERE user_connection."user" = $1"#,
+            id
+        )
+        .fetch_optional(&self.connection)
+        .await
+        .map_err(|x| {
+            error!("Error fetching user's team {x}");
+            Error::InternalError
+        })?;
+
+        Ok(UserDetails {
+            user,
+            team,
+            roles: self.get_user_roles(id.clone()).await?,
+            sessions: self.get_sessions(Some(id)).await?,
+        })
+    }
+
+    pub async fn is_user_disabled(&self, id: &str) -> Result<bool, Error> {
+        Ok(
+            sqlx::query_scalar!("SELECT disabled FROM users WHERE id = $1", id)
+                .fetch_optional(&self.connection)
+                .await
+                .map_err(|x| {
+                    error!("Error checking if user is disabled {x}");
+                    Error::InternalError
+                })?
+                // People who haven't used the site yet can't have been disabled
+                .unwrap_or(false),
+        )
+    }
+
+    /// Stops someone using the site, logging them out everywhere, or lets them back in
+    pub async fn set_user_disabled(
+        &self,
+        actor: &str,
+        id: String,
+        disabled: bool,
+    ) -> Result<(), Error> {
+        let mut transaction = self.begin().await?;
+
+        let result = sqlx::query!(
+            "UPDATE users SET disabled = $2 WHERE id = $1 AND disabled != $2",
+            id,
+            disabled
+        )
+        .execute(&mut transaction)
+        .await
+        .map_err(|x| {
+            error!("Error disabling user {x}");
+            Error::InternalError
+        })?;
+
+        if result.rows_affected() == 0 {
+            let exists = sqlx::query_scalar!(
+                r#"SELECT exists(SELECT 1 FROM users WHERE id = $1) AS "exists!""#,
+                id
+            )
+            .fetch_one(&mut transaction)
+            .await
+            .map_err(|x| {
+                error!("Error checking for user {x}");
+                Error::InternalError
+            })?;
+
+            // Already the way it was asked to be
+            return if exists {
+                Ok(())
+            } else {
+                Err(Error::NoSuchUser(id))
+            };
+        }
+
+        if disabled {
+            sqlx::query!(r#"DELETE FROM sessions WHERE "user" = $1"#, id)
+                .execute(&mut transaction)
+                .await
+                .map_err(|x| {
+                    error!("Error ending disabled user's sessions {x}");
+                    Error::InternalError
+                })?;
+            check_user_managers(&mut transaction).await?;
+        }
+
+        record(
+            &mut transaction,
+            actor,
+            if disabled {
+                "disable_user"
+            } else {
+                "enable_user"
+            },
+            &id,
+            json!({}),
+        )
+        .await?;
+        commit(transaction).await
+    }
+}
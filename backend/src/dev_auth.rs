@@ -0,0 +1,178 @@
+use chrono::Utc;
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::{thread_rng, RngCore};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::{auth::User, data::DevAuthConfig, error::*};
+
+/// The `iss` of tokens we issue ourselves
+const DEV_ISSUER: &str = "naff-dev";
+/// How long tokens we issue ourselves last, in seconds
+pub const DEV_TOKEN_EXPIRY: i64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize)]
+struct DevClaims {
+    #[serde(flatten)]
+    user: User,
+    iss: String,
+    exp: i64,
+}
+
+/// Issues and checks tokens for made up users, for developing and testing without an OIDC
+/// provider. Anyone can log in as anyone, so this must never be used in production.
+pub struct DevIssuer {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+impl DevIssuer {
+    /// Uses the configured secret, so tokens survive restarts, or a random one
+    pub fn new(config: &DevAuthConfig) -> Self {
+        let secret = match &config.secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                let mut secret = vec![0; 32];
+                thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+
+        warn!("Development login is enabled, anyone can log in as anyone");
+
+        Self {
+            encoding: EncodingKey::from_secret(&secret),
+            decoding: DecodingKey::from_secret(&secret),
+        }
+    }
+
+    pub fn issue(&self, user: User) -> Result<String, Error> {
+        let claims = DevClaims {
+            user,
+            iss: DEV_ISSUER.into(),
+            exp: Utc::now().timestamp() + DEV_TOKEN_EXPIRY,
+        };
+
+        encode(&Header::new(Algorithm::HS256), &claims, &self.encoding).map_err(|ex| {
+            error!("Couldn't issue development token {ex}");
+            Error::InternalError
+        })
+    }
+
+    pub fn verify(&self, token: &str) -> Result<User, Error> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[DEV_ISSUER]);
+
+        decode::<DevClaims>(token, &self.decoding, &validation)
+            .map(|x| x.claims.user)
+            .map_err(|_| {
+                warn!("Invalid development token");
+                Error::Unauthorized
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+    fn issuer(secret: Option<&str>) -> DevIssuer {
+        DevIssuer::new(&DevAuthConfig {
+            enabled: true,
+            secret: secret.map(Into::into),
+        })
+    }
+
+    fn user() -> User {
+        User {
+            id: "dev|alice".into(),
+            name: "Alice".into(),
+            email: "alice@example.com".into(),
+            email_verified: true,
+        }
+    }
+
+    /// A token signed by the issuer, with claims it wouldn't give out itself
+    fn token_with(issuer: &DevIssuer, iss: &str, exp: i64) -> String {
+        let claims = DevClaims {
+            user: user(),
+            iss: iss.into(),
+            exp,
+        };
+
+        encode(&Header::new(Algorithm::HS256), &claims, &issuer.encoding).unwrap()
+    }
+
+    fn assert_rejected(result: Result<User, Error>) {
+        assert!(
+            matches!(result, Err(Error::Unauthorized)),
+            "expected the token to be rejected, got {result:?}"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let issuer = issuer(None);
+        let verified = issuer.verify(&issuer.issue(user()).unwrap()).unwrap();
+
+        assert_eq!(verified.id, "dev|alice");
+        assert_eq!(verified.name, "Alice");
+        assert_eq!(verified.email, "alice@example.com");
+        assert!(verified.email_verified);
+    }
+
+    #[test]
+    fn configured_secret_survives_restarts() {
+        let token = issuer(Some("secret")).issue(user()).unwrap();
+
+        assert!(issuer(Some("secret")).verify(&token).is_ok());
+        assert_rejected(issuer(Some("another secret")).verify(&token));
+    }
+
+    #[test]
+    fn random_secrets_differ() {
+        let token = issuer(None).issue(user()).unwrap();
+
+        assert_rejected(issuer(None).verify(&token));
+    }
+
+    #[test]
+    fn tampered_token() {
+        let issuer = issuer(None);
+        let token = issuer.issue(user()).unwrap();
+
+        // Swap in claims for someone else, keeping the original signature
+        let claims = DevClaims {
+            user: User {
+                id: "dev|mallory".into(),
+                ..user()
+            },
+            iss: DEV_ISSUER.into(),
+            exp: Utc::now().timestamp() + DEV_TOKEN_EXPIRY,
+        };
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap());
+        let mut parts = token.split('.').collect::<Vec<_>>();
+        parts[1] = &claims;
+
+        assert_rejected(issuer.verify(&parts.join(".")));
+        assert_rejected(issuer.verify("not a token"));
+    }
+
+    #[test]
+    fn expired_token() {
+        let issuer = issuer(None);
+        // Expiry is checked with a minute's leeway
+        let token = token_with(&issuer, DEV_ISSUER, Utc::now().timestamp() - 120);
+
+        assert_rejected(issuer.verify(&token));
+    }
+
+    #[test]
+    fn other_issuer() {
+        let issuer = issuer(None);
+        let token = token_with(&issuer, "someone-else", Utc::now().timestamp() + 60);
+
+        assert_rejected(issuer.verify(&token));
+    }
+}
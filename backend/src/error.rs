@@ -1,6 +1,12 @@
 use std::{env::VarError, io, process::ExitCode};
 
-use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use actix_web::{
+    http::{
+        header::{ContentRange, ContentRangeSpec},
+        StatusCode,
+    },
+    HttpResponse, ResponseError,
+};
 use serde_json::json;
 use sqlx::PgPool;
 
@@ -19,13 +25,13 @@ impl<T> AsCreateError<T> for Result<T, sqlx::Error> {
 
 impl<T> AsCreateError<T> for Result<T, sqlx::migrate::MigrateError> {
     fn to_crate(self) -> Result<T, Error> {
-        self.map_err(|ex| Error::DbMigrationError(ex))
+        self.map_err(Error::DbMigrationError)
     }
 }
 
 impl<T> AsCreateError<T> for Result<T, envy::Error> {
     fn to_crate(self) -> Result<T, Error> {
-        self.map_err(|x| Error::EnvyError(x))
+        self.map_err(Error::EnvyError)
     }
 }
 
@@ -101,6 +107,119 @@ pub enum Error {
 
     #[error("Not allowed")]
     NotAllowed,
+
+    #[error("Invalid upload: {0}")]
+    InvalidUpload(String),
+
+    #[error("There is no film upload in progress for this team")]
+    NoUpload,
+
+    #[error("The upload is at byte {0}, resume it from there")]
+    UploadOffsetMismatch(i64),
+
+    #[error("Another part of this film is being uploaded, try again shortly")]
+    UploadBusy,
+
+    #[error("The uploaded film didn't match its checksum, please upload it again")]
+    ChecksumMismatch,
+
+    #[error("The team {0} hasn't uploaded a film yet")]
+    NoFilm(String),
+
+    #[error("The requested range is outside of the film")]
+    RangeNotSatisfiable(i64),
+
+    #[error("Invalid film details: {0}")]
+    InvalidFilmDetails(String),
+
+    #[error("Unsupported film: {0}")]
+    UnsupportedFilm(String),
+
+    #[error("Your film is {0:.0} seconds long, but films can't be longer than {1:.0} seconds")]
+    FilmTooLong(f64, f64),
+
+    #[error("Sorry, the deadline for {0} has passed")]
+    DeadlinePassed(String),
+
+    #[error("Registration for the festival hasn't opened yet")]
+    RegistrationNotOpen,
+
+    #[error("Invalid schedule: {0}")]
+    InvalidSchedule(String),
+
+    #[error("No category called {0} exists")]
+    NoSuchCategory(String),
+
+    #[error("The category {0} still has teams in it")]
+    CategoryInUse(String),
+
+    #[error("Your team can't enter the {0} category, {1}")]
+    NotEligible(String, String),
+
+    #[error("Invalid category: {0}")]
+    InvalidCategory(String),
+
+    #[error("Your login expired or didn't start on this site, please try logging in again")]
+    InvalidLoginState,
+
+    #[error("Logging in isn't working right now, please try again later")]
+    AuthUnavailable,
+
+    #[error("Invalid role: {0}")]
+    InvalidRole(String),
+
+    #[error("No role called {0} exists")]
+    NoSuchRole(String),
+
+    #[error("No user with the id {0} exists")]
+    NoSuchUser(String),
+
+    #[error("Nobody would be left who can manage users")]
+    WouldLockOut,
+
+    #[error(
+        "This account has been disabled, contact the organisers if you think this is a mistake"
+    )]
+    AccountDisabled,
+
+    #[error("Invalid team change: {0}")]
+    InvalidTeamChange(String),
+
+    #[error("The team {0} has uploaded a film, which would be lost")]
+    TeamHasFilm(String),
+
+    #[error("Only the team's captain can do that")]
+    NotCaptain,
+
+    #[error("{0} isn't in the team")]
+    NotTeamMember(String),
+
+    #[error("Couldn't send the email, try again later")]
+    MailUnavailable,
+
+    #[error("There's no unsent email {0}")]
+    NoSuchEmail(i64),
+
+    #[error("{0} isn't a valid email address")]
+    InvalidEmail(String),
+
+    #[error("This invite is for {0}, log in with that email address to accept it")]
+    WrongInviteEmail(String),
+
+    #[error("The invite doesn't exist or has expired")]
+    NoSuchInvite,
+
+    #[error("Your email address hasn't been verified, verify it then use the invite again")]
+    UnverifiedEmail,
+
+    #[error("{0} hasn't asked to join the team")]
+    NoJoinRequest(String),
+
+    #[error("The team {0} is full, teams can have at most {1} members")]
+    TeamFull(String, i32),
+
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
 }
 
 impl Error {
@@ -110,7 +229,44 @@ impl Error {
             Error::ServerStartError(_) => 2,
             Error::DbConnectError(_) => 3,
             Error::DbQueryError(_) | Error::DbMigrationError(_) => 4,
+            Error::InvalidConfig(_) => 5,
 
+            Error::UnverifiedEmail => 202,
+            Error::NoSuchEmail(_) => 203,
+            Error::MailUnavailable => 204,
+            Error::InvalidEmail(_) => 205,
+            Error::WrongInviteEmail(_) => 206,
+            Error::NoSuchInvite => 207,
+            Error::NoJoinRequest(_) => 208,
+            Error::TeamFull(..) => 209,
+            Error::NotTeamMember(_) => 210,
+            Error::NotCaptain => 211,
+            Error::TeamHasFilm(_) => 212,
+            Error::InvalidTeamChange(_) => 213,
+            Error::AccountDisabled => 214,
+            Error::WouldLockOut => 215,
+            Error::NoSuchUser(_) => 216,
+            Error::NoSuchRole(_) => 217,
+            Error::InvalidRole(_) => 218,
+            Error::AuthUnavailable => 219,
+            Error::InvalidLoginState => 220,
+            Error::InvalidCategory(_) => 221,
+            Error::NotEligible(..) => 222,
+            Error::CategoryInUse(_) => 223,
+            Error::NoSuchCategory(_) => 224,
+            Error::InvalidSchedule(_) => 225,
+            Error::RegistrationNotOpen => 226,
+            Error::DeadlinePassed(_) => 227,
+            Error::FilmTooLong(..) => 228,
+            Error::UnsupportedFilm(_) => 229,
+            Error::InvalidFilmDetails(_) => 230,
+            Error::RangeNotSatisfiable(_) => 231,
+            Error::NoFilm(_) => 232,
+            Error::ChecksumMismatch => 233,
+            Error::UploadBusy => 234,
+            Error::UploadOffsetMismatch(_) => 235,
+            Error::NoUpload => 236,
+            Error::InvalidUpload(_) => 237,
             Error::NotInTeam => 238,
             Error::TeamAccessDenied(_) => 239,
             Error::TeamNameTaken(_) => 240,
@@ -131,17 +287,63 @@ impl Error {
 impl ResponseError for Error {
     fn status_code(&self) -> StatusCode {
         match self {
-            Error::InTeam | Error::NoSuchTeam(_) | Error::NotInTeam => StatusCode::BAD_REQUEST,
+            Error::InTeam
+            | Error::NoSuchTeam(_)
+            | Error::NotInTeam
+            | Error::InvalidUpload(_)
+            | Error::NoUpload
+            | Error::ChecksumMismatch
+            | Error::InvalidFilmDetails(_)
+            | Error::UnsupportedFilm(_)
+            | Error::FilmTooLong(..)
+            | Error::InvalidSchedule(_)
+            | Error::NoSuchCategory(_)
+            | Error::InvalidCategory(_)
+            | Error::InvalidLoginState
+            | Error::InvalidRole(_)
+            | Error::NoSuchRole(_)
+            | Error::NoSuchUser(_)
+            | Error::InvalidTeamChange(_)
+            | Error::NotTeamMember(_)
+            | Error::NoJoinRequest(_)
+            | Error::InvalidEmail(_)
+            | Error::NoSuchEmail(_)
+            | Error::NoSuchInvite => StatusCode::BAD_REQUEST,
+            Error::UploadOffsetMismatch(_)
+            | Error::UploadBusy
+            | Error::CategoryInUse(_)
+            | Error::WouldLockOut
+            | Error::TeamHasFilm(_)
+            | Error::TeamFull(..) => StatusCode::CONFLICT,
+            Error::NoFilm(_) => StatusCode::NOT_FOUND,
+            Error::RangeNotSatisfiable(_) => StatusCode::RANGE_NOT_SATISFIABLE,
             Error::Unauthorized => StatusCode::UNAUTHORIZED,
             Error::TeamAccessDenied(_) => StatusCode::FORBIDDEN,
             Error::NotImplemented => StatusCode::NOT_IMPLEMENTED,
+            Error::AuthUnavailable | Error::MailUnavailable => StatusCode::SERVICE_UNAVAILABLE,
             Error::NotAllowed => StatusCode::FORBIDDEN,
+            Error::DeadlinePassed(_)
+            | Error::RegistrationNotOpen
+            | Error::NotEligible(..)
+            | Error::AccountDisabled
+            | Error::NotCaptain
+            | Error::WrongInviteEmail(_)
+            | Error::UnverifiedEmail => StatusCode::FORBIDDEN,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code()).json(json!({
+        let mut response = HttpResponse::build(self.status_code());
+
+        if let Error::RangeNotSatisfiable(size) = self {
+            response.insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: None,
+                instance_length: Some(*size as u64),
+            }));
+        }
+
+        response.json(json!({
             "code": self.as_number(),
             "error": format!("{self}")
         }))
@@ -0,0 +1,86 @@
+use super::{storage_error, FilmStorage, FilmStream};
+use crate::error::*;
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use std::{
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+use tracing::info;
+
+/// Keeps films in the `films` directory under the storage path, named after their key. They have
+/// their own directory so a key can't clash with anything else kept there, like partial uploads.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub async fn new(path: &Path) -> Result<Self, Error> {
+        let root = path.join("films");
+        fs::create_dir_all(&root)
+            .await
+            .map_err(Error::ServerStartError)?;
+
+        // Films used to be kept right in the storage path
+        let mut entries = fs::read_dir(path).await.map_err(Error::ServerStartError)?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(Error::ServerStartError)?
+        {
+            if entry
+                .file_type()
+                .await
+                .map_err(Error::ServerStartError)?
+                .is_file()
+            {
+                info!(
+                    "Moving film {} into {}",
+                    entry.path().display(),
+                    root.display()
+                );
+                fs::rename(entry.path(), root.join(entry.file_name()))
+                    .await
+                    .map_err(Error::ServerStartError)?;
+            }
+        }
+
+        Ok(Self { root })
+    }
+
+    fn film_path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[async_trait]
+impl FilmStorage for LocalStorage {
+    async fn store(&self, key: &str, file: &Path) -> Result<(), Error> {
+        fs::rename(file, self.film_path(key))
+            .await
+            .map_err(storage_error)
+    }
+
+    async fn read(&self, key: &str, start: u64, len: u64) -> Result<FilmStream, Error> {
+        let mut file = File::open(self.film_path(key))
+            .await
+            .map_err(storage_error)?;
+        file.seek(SeekFrom::Start(start))
+            .await
+            .map_err(storage_error)?;
+
+        Ok(ReaderStream::new(file.take(len)).boxed())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), Error> {
+        match fs::remove_file(self.film_path(key)).await {
+            Err(ex) if ex.kind() != io::ErrorKind::NotFound => Err(storage_error(ex)),
+            _ => Ok(()),
+        }
+    }
+}
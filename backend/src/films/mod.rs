@@ -0,0 +1,242 @@
+use crate::{
+    data::{random_token, StorageBackend, StorageConfig},
+    error::*,
+};
+use actix_web::{error::PayloadError, web::Bytes};
+use async_trait::async_trait;
+use futures_util::{stream::BoxStream, Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, Read, SeekFrom},
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{AsyncSeekExt, AsyncWriteExt},
+    time::{timeout_at, Instant},
+};
+use tracing::{error, info, warn};
+
+mod local;
+mod probe;
+mod s3;
+
+pub use self::{
+    local::LocalStorage,
+    probe::{FilmInfo, ProbeError},
+    s3::S3Storage,
+};
+
+pub type FilmStream = BoxStream<'static, Result<Bytes, io::Error>>;
+
+fn storage_error(ex: io::Error) -> Error {
+    error!("Film storage error {ex}");
+    Error::InternalError
+}
+
+/// Guesses the MIME type of a film from its file name
+pub fn content_type(file_name: &str) -> &'static str {
+    let extension = file_name
+        .rsplit_once('.')
+        .map(|(_, x)| x.to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mov" => "video/quicktime",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Somewhere finished films are kept, each under its own key
+#[async_trait]
+pub trait FilmStorage: Send + Sync {
+    /// Stores a film from a local file under `key`. The file may be moved or deleted.
+    async fn store(&self, key: &str, file: &Path) -> Result<(), Error>;
+
+    /// Streams `len` bytes of the film under `key`, starting at `start`
+    async fn read(&self, key: &str, start: u64, len: u64) -> Result<FilmStream, Error>;
+
+    /// Deletes the film under `key`, if there is one
+    async fn remove(&self, key: &str) -> Result<(), Error>;
+}
+
+/// Assembles uploads on the local disk, then hands the finished films to a [`FilmStorage`]
+pub struct FilmStore {
+    partial: PathBuf,
+    storage: Box<dyn FilmStorage>,
+}
+
+impl FilmStore {
+    pub async fn new(config: &StorageConfig) -> Result<Self, Error> {
+        let root = PathBuf::from(&config.path);
+        let partial = root.join("partial");
+
+        fs::create_dir_all(&partial)
+            .await
+            .map_err(Error::ServerStartError)?;
+
+        let storage: Box<dyn FilmStorage> = match config.backend {
+            StorageBackend::Local => {
+                info!("Storing films in {}", root.display());
+                Box::new(LocalStorage::new(&root).await?)
+            }
+            StorageBackend::S3 => Box::new(S3Storage::new(config)?),
+        };
+
+        Ok(Self { partial, storage })
+    }
+
+    fn partial_path(&self, team: &str) -> PathBuf {
+        self.partial.join(team)
+    }
+
+    /// How many bytes of a team's upload are actually on disk
+    pub async fn partial_len(&self, team: &str) -> Result<u64, Error> {
+        match fs::metadata(self.partial_path(team)).await {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(ex) if ex.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(ex) => Err(storage_error(ex)),
+        }
+    }
+
+    pub async fn discard_partial(&self, team: &str) -> Result<(), Error> {
+        match fs::remove_file(self.partial_path(team)).await {
+            Err(ex) if ex.kind() != io::ErrorKind::NotFound => Err(storage_error(ex)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes a chunk of a team's film starting at `offset`, throwing away anything after it that
+    /// was left behind by an interrupted chunk. Nothing is written after `deadline`, so the chunk
+    /// can't carry on once someone else could be writing to the upload. Returns the new length of
+    /// the upload.
+    pub async fn write_chunk<S>(
+        &self,
+        team: &str,
+        offset: u64,
+        limit: u64,
+        deadline: Instant,
+        mut chunk: S,
+    ) -> Result<u64, Error>
+    where
+        S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+    {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.partial_path(team))
+            .await
+            .map_err(storage_error)?;
+
+        file.set_len(offset).await.map_err(storage_error)?;
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(storage_error)?;
+
+        let mut written = 0;
+        loop {
+            let Ok(bytes) = timeout_at(deadline, chunk.next()).await else {
+                warn!("Film chunk for {team} took too long");
+                return Err(Error::InvalidUpload(
+                    "the chunk took too long, please send smaller chunks".into(),
+                ));
+            };
+            let Some(bytes) = bytes else {
+                break;
+            };
+            let bytes = bytes.map_err(|ex| {
+                warn!("Film chunk for {team} was interrupted {ex}");
+                Error::InvalidUpload("the chunk was interrupted, please resend it".into())
+            })?;
+
+            written += bytes.len() as u64;
+            if written > limit {
+                file.set_len(offset).await.map_err(storage_error)?;
+                return Err(Error::InvalidUpload(format!(
+                    "the chunk is too big, it can be at most {limit} bytes"
+                )));
+            }
+
+            file.write_all(&bytes).await.map_err(storage_error)?;
+        }
+
+        file.sync_data().await.map_err(storage_error)?;
+
+        Ok(offset + written)
+    }
+
+    /// Hex encoded SHA-256 of a team's partial upload
+    pub async fn checksum_partial(&self, team: &str) -> Result<String, Error> {
+        let path = self.partial_path(team);
+
+        tokio::task::spawn_blocking(move || {
+            let mut file = std::fs::File::open(path)?;
+            let mut hasher = Sha256::new();
+            let mut buffer = vec![0; 1024 * 1024];
+
+            loop {
+                let read = file.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..read]);
+            }
+
+            Ok(hex::encode(hasher.finalize()))
+        })
+        .await
+        .map_err(|ex| {
+            error!("Checksum task failed {ex}");
+            Error::InternalError
+        })?
+        .map_err(storage_error)
+    }
+
+    /// Reads the details of a team's partial upload out of its container
+    pub async fn probe_partial(&self, team: &str) -> Result<FilmInfo, Error> {
+        let path = self.partial_path(team);
+
+        tokio::task::spawn_blocking(move || probe::probe(&path))
+            .await
+            .map_err(|ex| {
+                error!("Probe task failed {ex}");
+                Error::InternalError
+            })?
+            .map_err(|ex| match ex {
+                ProbeError::Io(ex) => storage_error(ex),
+                ProbeError::Unsupported(reason) => Error::UnsupportedFilm(reason),
+            })
+    }
+
+    /// Hands a finished upload over to storage under a new key, which nothing refers to until it
+    /// is recorded against the team's film. Returns the key.
+    pub async fn commit(&self, team: &str) -> Result<String, Error> {
+        let key = format!("{team}.{}", random_token());
+        self.storage.store(&key, &self.partial_path(team)).await?;
+
+        Ok(key)
+    }
+
+    pub async fn read(&self, key: &str, start: u64, len: u64) -> Result<FilmStream, Error> {
+        self.storage.read(key, start, len).await
+    }
+
+    /// Deletes a stored film
+    pub async fn remove_film(&self, key: &str) -> Result<(), Error> {
+        self.storage.remove(key).await
+    }
+
+    /// Deletes everything a team uploaded, finished or not
+    pub async fn remove(&self, team: &str, key: Option<&str>) -> Result<(), Error> {
+        self.discard_partial(team).await?;
+        match key {
+            Some(key) => self.remove_film(key).await,
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,760 @@
+//! Reads a film's duration, resolution, codec and frame rate straight out of its container,
+//! without decoding any video. MP4/MOV (ISO base media files) and Matroska/WebM are supported.
+
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// The `moov` box only holds metadata, so anything bigger than this isn't a film we can handle
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+/// Same goes for Matroska's `Info` and `Tracks` elements
+const MAX_MATROSKA_ELEMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+const EBML_HEADER: u32 = 0x1A45DFA3;
+const EBML_DOC_TYPE: u32 = 0x4282;
+const SEGMENT: u32 = 0x18538067;
+const CLUSTER: u32 = 0x1F43B675;
+const INFO: u32 = 0x1549A966;
+const TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+const DURATION: u32 = 0x4489;
+const TRACKS: u32 = 0x1654AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_TYPE: u32 = 0x83;
+const CODEC_ID: u32 = 0x86;
+const DEFAULT_DURATION: u32 = 0x23E383;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FilmInfo {
+    /// `mp4`, `mov`, `matroska` or `webm`
+    pub container: String,
+    /// In seconds
+    pub duration: f64,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub video_codec: Option<String>,
+    pub frame_rate: Option<f64>,
+}
+
+#[derive(Debug)]
+pub enum ProbeError {
+    Io(io::Error),
+    /// Not a film we understand, with a reason that can be shown to the team
+    Unsupported(String),
+}
+
+impl From<io::Error> for ProbeError {
+    fn from(ex: io::Error) -> Self {
+        match ex.kind() {
+            // Running out of file half way through a box means the file is broken, not the disk
+            io::ErrorKind::UnexpectedEof => {
+                ProbeError::Unsupported("the file ends part way through its metadata".into())
+            }
+            _ => ProbeError::Io(ex),
+        }
+    }
+}
+
+fn unsupported<T>(reason: &str) -> Result<T, ProbeError> {
+    Err(ProbeError::Unsupported(reason.into()))
+}
+
+/// Works out what kind of container a film is in and reads its details. This does blocking IO.
+pub fn probe(path: &Path) -> Result<FilmInfo, ProbeError> {
+    let mut file = BufReader::new(File::open(path)?);
+    let len = file.get_ref().metadata()?.len();
+
+    let mut magic = [0; 8];
+    file.read_exact(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    if magic[..4] == EBML_HEADER.to_be_bytes() {
+        probe_matroska(&mut file, len)
+    } else if matches!(
+        &magic[4..],
+        b"ftyp" | b"moov" | b"mdat" | b"free" | b"skip" | b"wide" | b"pnot"
+    ) {
+        probe_iso(&mut file, len)
+    } else {
+        unsupported("films must be MP4, MOV, MKV or WebM files")
+    }
+}
+
+fn round(value: f64) -> f64 {
+    (value * 1000.0).round() / 1000.0
+}
+
+fn be_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn be_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+/// Iterates over the ISO boxes in `data`, yielding their type and contents
+fn boxes(data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut at = 0;
+
+    std::iter::from_fn(move || {
+        let kind: [u8; 4] = data.get(at + 4..at + 8)?.try_into().ok()?;
+        let (header, size) = match be_u32(data, at)? {
+            1 => (16, usize::try_from(be_u64(data, at + 8)?).ok()?),
+            0 => (8, data.len() - at),
+            size => (8, size as usize),
+        };
+
+        let body = data.get(at + header..at.checked_add(size)?)?;
+        at += size;
+
+        Some((kind, body))
+    })
+}
+
+fn find_box<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    path.iter().try_fold(data, |data, kind| {
+        boxes(data).find(|(x, _)| x == *kind).map(|(_, body)| body)
+    })
+}
+
+/// Reads the timescale and duration out of a `mvhd` or `mdhd` box, which share a layout
+fn header_duration(data: &[u8]) -> Option<(u32, u64)> {
+    match data.first()? {
+        1 => Some((be_u32(data, 20)?, be_u64(data, 24)?)),
+        _ => Some((be_u32(data, 12)?, be_u32(data, 16)? as u64)),
+    }
+}
+
+fn probe_iso<R: Read + Seek>(file: &mut R, len: u64) -> Result<FilmInfo, ProbeError> {
+    let mut brand = None;
+    let mut moov = None;
+    let mut at = 0;
+
+    // Walk the top level boxes, skipping over the media data which makes up most of the file
+    while at + 8 <= len {
+        file.seek(SeekFrom::Start(at))?;
+        let mut header = [0; 8];
+        file.read_exact(&mut header)?;
+
+        let mut header_len = 8;
+        let size = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            1 => {
+                let mut large = [0; 8];
+                file.read_exact(&mut large)?;
+                header_len = 16;
+                u64::from_be_bytes(large)
+            }
+            0 => len - at,
+            size => size as u64,
+        };
+        let end = match at.checked_add(size) {
+            Some(end) if size >= header_len && end <= len => end,
+            _ => return unsupported("the file is corrupt or wasn't fully uploaded"),
+        };
+
+        match &header[4..] {
+            b"ftyp" => {
+                let mut major = [0; 4];
+                file.read_exact(&mut major)?;
+                brand = Some(major);
+            }
+            b"moov" => {
+                if size > MAX_MOOV_SIZE {
+                    return unsupported("the film's metadata is too large");
+                }
+                let mut body = vec![0; (size - header_len) as usize];
+                file.read_exact(&mut body)?;
+                moov = Some(body);
+            }
+            _ => {}
+        }
+
+        at = end;
+    }
+
+    let moov = match moov {
+        Some(moov) => moov,
+        None => return unsupported("the file has no movie metadata"),
+    };
+
+    let (timescale, mut duration) = find_box(&moov, &[b"mvhd"])
+        .and_then(header_duration)
+        .unwrap_or((0, 0));
+
+    // Fragmented files keep their overall duration in the movie extends header instead
+    if duration == 0 {
+        if let Some(mehd) = find_box(&moov, &[b"mvex", b"mehd"]) {
+            duration = match mehd.first() {
+                Some(1) => be_u64(mehd, 4).unwrap_or(0),
+                _ => be_u32(mehd, 4).unwrap_or(0) as u64,
+            };
+        }
+    }
+
+    if timescale == 0 || duration == 0 {
+        return unsupported("couldn't work out how long the film is");
+    }
+
+    let mut info = FilmInfo {
+        container: match brand {
+            Some(brand) if &brand != b"qt  " => "mp4",
+            _ => "mov",
+        }
+        .into(),
+        duration: round(duration as f64 / timescale as f64),
+        width: None,
+        height: None,
+        video_codec: None,
+        frame_rate: None,
+    };
+
+    let video = boxes(&moov)
+        .filter(|(kind, _)| kind == b"trak")
+        .filter_map(|(_, trak)| find_box(trak, &[b"mdia"]))
+        .find(|mdia| {
+            find_box(mdia, &[b"hdlr"]).and_then(|x| x.get(8..12)) == Some(b"vide".as_slice())
+        });
+
+    if let Some(mdia) = video {
+        // The first sample description says which codec the track uses and its size in pixels
+        if let Some(entry) = find_box(mdia, &[b"minf", b"stbl", b"stsd"]).and_then(|x| x.get(8..)) {
+            info.video_codec = entry
+                .get(4..8)
+                .map(|x| String::from_utf8_lossy(x).trim().to_owned());
+            info.width = be_u16(entry, 32).map(i32::from);
+            info.height = be_u16(entry, 34).map(i32::from);
+        }
+
+        // Frame rate is the number of samples over how long the track runs for. The count can't
+        // be trusted, so it only goes as far as the entries which are actually there.
+        let samples = find_box(mdia, &[b"minf", b"stbl", b"stts"]).map(|stts| {
+            let count = be_u32(stts, 4).unwrap_or(0) as usize;
+            stts.get(8..)
+                .unwrap_or_default()
+                .chunks_exact(8)
+                .take(count)
+                .filter_map(|entry| be_u32(entry, 0))
+                .map(u64::from)
+                .sum::<u64>()
+        });
+
+        if let (Some(samples), Some((timescale, duration))) = (
+            samples,
+            find_box(mdia, &[b"mdhd"]).and_then(header_duration),
+        ) {
+            if samples > 0 && timescale > 0 && duration > 0 {
+                info.frame_rate = Some(round(samples as f64 * timescale as f64 / duration as f64));
+            }
+        }
+    }
+
+    Ok(info)
+}
+
+/// Reads an EBML variable length integer from the start of `data`, returning it and its length.
+/// IDs keep their length marker bit, sizes don't.
+fn vint(data: &[u8], keep_marker: bool) -> Option<(u64, usize)> {
+    let first = *data.first()?;
+    if first == 0 {
+        return None;
+    }
+
+    let len = first.leading_zeros() as usize + 1;
+    let mut value = if keep_marker {
+        first as u64
+    } else {
+        first as u64 & (0xFF >> len)
+    };
+    for byte in data.get(1..len)? {
+        value = (value << 8) | *byte as u64;
+    }
+
+    Some((value, len))
+}
+
+/// Iterates over the EBML elements in `data`, yielding their ID and contents
+fn elements(data: &[u8]) -> impl Iterator<Item = (u32, &[u8])> {
+    let mut at = 0;
+
+    std::iter::from_fn(move || {
+        let (id, id_len) = vint(data.get(at..)?, true)?;
+        let (size, size_len) = vint(data.get(at + id_len..)?, false)?;
+        let start = at + id_len + size_len;
+        let body = data.get(start..start.checked_add(usize::try_from(size).ok()?)?)?;
+        at = start + body.len();
+
+        Some((id as u32, body))
+    })
+}
+
+fn find_element(data: &[u8], id: u32) -> Option<&[u8]> {
+    elements(data).find(|(x, _)| *x == id).map(|(_, body)| body)
+}
+
+fn ebml_uint(data: &[u8]) -> Option<u64> {
+    if data.len() > 8 {
+        return None;
+    }
+    Some(
+        data.iter()
+            .fold(0, |value, byte| (value << 8) | *byte as u64),
+    )
+}
+
+fn ebml_float(data: &[u8]) -> Option<f64> {
+    match data.len() {
+        4 => Some(f32::from_be_bytes(data.try_into().ok()?) as f64),
+        8 => Some(f64::from_be_bytes(data.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+/// Reads an element header from a file, returning its ID and size. Unknown sizes are `None`.
+fn read_element_header<R: Read>(file: &mut R) -> Result<(u32, Option<u64>), ProbeError> {
+    // IDs are at most 4 bytes and sizes 8, but they have to be read a byte at a time as we don't
+    // know how long they are until the first byte has been read
+    let mut read_vint = |keep_marker: bool| -> Result<(u64, usize), ProbeError> {
+        let mut bytes = [0; 8];
+        file.read_exact(&mut bytes[..1])?;
+        let len = bytes[0].leading_zeros() as usize + 1;
+        if len > 8 {
+            return unsupported("the file is corrupt");
+        }
+        file.read_exact(&mut bytes[1..len])?;
+        vint(&bytes[..len], keep_marker)
+            .ok_or(ProbeError::Unsupported("the file is corrupt".into()))
+    };
+
+    let (id, _) = read_vint(true)?;
+    let (size, len) = read_vint(false)?;
+    // A size with every bit set means the element runs until its parent ends
+    let unknown = size == (1 << (7 * len)) - 1;
+
+    Ok((id as u32, if unknown { None } else { Some(size) }))
+}
+
+fn read_element<R: Read>(file: &mut R, size: u64) -> Result<Vec<u8>, ProbeError> {
+    if size > MAX_MATROSKA_ELEMENT_SIZE {
+        return unsupported("the film's metadata is too large");
+    }
+    let mut body = vec![0; size as usize];
+    file.read_exact(&mut body)?;
+    Ok(body)
+}
+
+fn probe_matroska<R: Read + Seek>(file: &mut R, len: u64) -> Result<FilmInfo, ProbeError> {
+    let container = match read_element_header(file)? {
+        (EBML_HEADER, Some(size)) => {
+            let header = read_element(file, size)?;
+            match find_element(&header, EBML_DOC_TYPE) {
+                Some(b"webm") => "webm",
+                Some(b"matroska") => "matroska",
+                _ => return unsupported("films must be MP4, MOV, MKV or WebM files"),
+            }
+        }
+        _ => return unsupported("the file is corrupt"),
+    };
+
+    let segment_end = match read_element_header(file)? {
+        (SEGMENT, Some(size)) => match file.stream_position()?.checked_add(size) {
+            Some(end) => end,
+            None => return unsupported("the file is corrupt"),
+        },
+        (SEGMENT, None) => len,
+        _ => return unsupported("the file has no segment"),
+    };
+
+    let mut info = None;
+    let mut tracks = None;
+
+    // Info and Tracks almost always come before the first cluster, but seek past anything else
+    // just in case they don't
+    while info.is_none() || tracks.is_none() {
+        if file.stream_position()? >= segment_end {
+            break;
+        }
+
+        match read_element_header(file)? {
+            (INFO, Some(size)) => info = Some(read_element(file, size)?),
+            (TRACKS, Some(size)) => tracks = Some(read_element(file, size)?),
+            (_, Some(size)) => {
+                file.seek(SeekFrom::Current(size as i64))?;
+            }
+            // A cluster which is still being written, nothing useful comes after this
+            (CLUSTER, None) => break,
+            (_, None) => return unsupported("the file is corrupt"),
+        }
+    }
+
+    let info = match info {
+        Some(info) => info,
+        None => return unsupported("the file has no segment information"),
+    };
+
+    let scale = find_element(&info, TIMESTAMP_SCALE)
+        .and_then(ebml_uint)
+        .unwrap_or(1_000_000);
+    let duration = match find_element(&info, DURATION).and_then(ebml_float) {
+        Some(duration) if duration > 0.0 => duration * scale as f64 / 1e9,
+        _ => return unsupported("couldn't work out how long the film is"),
+    };
+
+    let mut film = FilmInfo {
+        container: container.into(),
+        duration: round(duration),
+        width: None,
+        height: None,
+        video_codec: None,
+        frame_rate: None,
+    };
+
+    let video = tracks.as_deref().and_then(|tracks| {
+        elements(tracks)
+            .filter(|(id, _)| *id == TRACK_ENTRY)
+            .map(|(_, entry)| entry)
+            .find(|entry| find_element(entry, TRACK_TYPE).and_then(ebml_uint) == Some(1))
+    });
+
+    if let Some(entry) = video {
+        film.video_codec =
+            find_element(entry, CODEC_ID).map(|x| String::from_utf8_lossy(x).into_owned());
+        film.frame_rate = find_element(entry, DEFAULT_DURATION)
+            .and_then(ebml_uint)
+            .filter(|x| *x > 0)
+            .map(|x| round(1e9 / x as f64));
+
+        if let Some(video) = find_element(entry, VIDEO) {
+            film.width = find_element(video, PIXEL_WIDTH)
+                .and_then(ebml_uint)
+                .and_then(|x| i32::try_from(x).ok());
+            film.height = find_element(video, PIXEL_HEIGHT)
+                .and_then(ebml_uint)
+                .and_then(|x| i32::try_from(x).ok());
+        }
+    }
+
+    Ok(film)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn iso_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(body);
+        data
+    }
+
+    /// A version 0 `mvhd` or `mdhd`
+    fn header_box(kind: &[u8; 4], timescale: u32, duration: u32) -> Vec<u8> {
+        let mut body = vec![0; 12];
+        body.extend_from_slice(&timescale.to_be_bytes());
+        body.extend_from_slice(&duration.to_be_bytes());
+        body.resize(body.len() + 80, 0);
+        iso_box(kind, &body)
+    }
+
+    fn stts(count: u32, entries: &[(u32, u32)]) -> Vec<u8> {
+        let mut body = vec![0; 4];
+        body.extend_from_slice(&count.to_be_bytes());
+        for (samples, delta) in entries {
+            body.extend_from_slice(&samples.to_be_bytes());
+            body.extend_from_slice(&delta.to_be_bytes());
+        }
+        iso_box(b"stts", &body)
+    }
+
+    /// A 5 second 1920x1080 H.264 MP4 at 25 frames a second, with the `stts` given
+    fn mp4_with_stts(stts: Vec<u8>) -> Vec<u8> {
+        let mut hdlr = vec![0; 8];
+        hdlr.extend_from_slice(b"vide");
+        hdlr.resize(24, 0);
+
+        let mut entry = vec![0; 4];
+        entry.extend_from_slice(b"avc1");
+        entry.resize(32, 0);
+        entry.extend_from_slice(&1920u16.to_be_bytes());
+        entry.extend_from_slice(&1080u16.to_be_bytes());
+        entry.resize(86, 0);
+        let mut stsd = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        stsd.extend_from_slice(&entry);
+
+        let stbl = iso_box(b"stbl", &[iso_box(b"stsd", &stsd), stts].concat());
+        let mdia = iso_box(
+            b"mdia",
+            &[
+                header_box(b"mdhd", 12800, 64000),
+                iso_box(b"hdlr", &hdlr),
+                iso_box(b"minf", &stbl),
+            ]
+            .concat(),
+        );
+        let moov = iso_box(
+            b"moov",
+            &[header_box(b"mvhd", 1000, 5000), iso_box(b"trak", &mdia)].concat(),
+        );
+
+        [
+            iso_box(b"ftyp", b"isom\0\0\x02\0"),
+            moov,
+            iso_box(b"mdat", &[0; 64]),
+        ]
+        .concat()
+    }
+
+    fn mp4() -> Vec<u8> {
+        mp4_with_stts(stts(1, &[(125, 512)]))
+    }
+
+    fn probe_iso_bytes(data: &[u8]) -> Result<FilmInfo, ProbeError> {
+        probe_iso(&mut Cursor::new(data), data.len() as u64)
+    }
+
+    /// An EBML element, with its size always written as 8 bytes
+    fn element(id: u32, body: &[u8]) -> Vec<u8> {
+        let mut data: Vec<u8> = id
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|x| *x == 0)
+            .collect();
+        data.push(0x01);
+        data.extend_from_slice(&(body.len() as u64).to_be_bytes()[1..]);
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn webm_with_segment(segment: Vec<u8>) -> Vec<u8> {
+        [
+            element(EBML_HEADER, &element(EBML_DOC_TYPE, b"webm")),
+            segment,
+        ]
+        .concat()
+    }
+
+    fn segment_body() -> Vec<u8> {
+        let info = element(
+            INFO,
+            &[
+                element(TIMESTAMP_SCALE, &[0x0F, 0x42, 0x40]),
+                element(DURATION, &5000f64.to_be_bytes()),
+            ]
+            .concat(),
+        );
+        let video = element(
+            VIDEO,
+            &[
+                element(PIXEL_WIDTH, &1280u16.to_be_bytes()),
+                element(PIXEL_HEIGHT, &720u16.to_be_bytes()),
+            ]
+            .concat(),
+        );
+        let tracks = element(
+            TRACKS,
+            &element(
+                TRACK_ENTRY,
+                &[
+                    element(TRACK_TYPE, &[1]),
+                    element(CODEC_ID, b"V_VP9"),
+                    element(DEFAULT_DURATION, &40_000_000u32.to_be_bytes()),
+                    video,
+                ]
+                .concat(),
+            ),
+        );
+
+        [info, tracks, element(CLUSTER, &[0; 16])].concat()
+    }
+
+    fn webm() -> Vec<u8> {
+        webm_with_segment(element(SEGMENT, &segment_body()))
+    }
+
+    fn probe_matroska_bytes(data: &[u8]) -> Result<FilmInfo, ProbeError> {
+        probe_matroska(&mut Cursor::new(data), data.len() as u64)
+    }
+
+    fn assert_unsupported(result: Result<FilmInfo, ProbeError>) {
+        assert!(
+            matches!(result, Err(ProbeError::Unsupported(_))),
+            "expected the film to be rejected, got {result:?}"
+        );
+    }
+
+    #[test]
+    fn reads_mp4() {
+        let info = probe_iso_bytes(&mp4()).unwrap();
+
+        assert_eq!(info.container, "mp4");
+        assert_eq!(info.duration, 5.0);
+        assert_eq!(info.video_codec.as_deref(), Some("avc1"));
+        assert_eq!((info.width, info.height), (Some(1920), Some(1080)));
+        assert_eq!(info.frame_rate, Some(25.0));
+    }
+
+    #[test]
+    fn rejects_truncated_mp4() {
+        let data = mp4();
+        // Everything up to part way through the moov, which comes before the media data
+        for len in [4, 12, 40, data.len() - 80] {
+            assert_unsupported(probe_iso_bytes(&data[..len]));
+        }
+    }
+
+    #[test]
+    fn rejects_box_past_end_of_file() {
+        let mut data = mp4();
+        // The mdat claims to be bigger than what's left
+        let mdat = data.len() - 72;
+        data[mdat..mdat + 4].copy_from_slice(&1000u32.to_be_bytes());
+
+        assert_unsupported(probe_iso_bytes(&data));
+    }
+
+    #[test]
+    fn rejects_overflowing_large_box() {
+        let mut data = 1u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"ftyp");
+        data.extend_from_slice(&u64::MAX.to_be_bytes());
+        data.extend_from_slice(&[0; 16]);
+
+        assert_unsupported(probe_iso_bytes(&data));
+    }
+
+    #[test]
+    fn rejects_box_smaller_than_its_header() {
+        let mut data = mp4();
+        data[..4].copy_from_slice(&3u32.to_be_bytes());
+
+        assert_unsupported(probe_iso_bytes(&data));
+    }
+
+    #[test]
+    fn rejects_oversized_moov() {
+        let mut data = iso_box(b"ftyp", b"isom\0\0\x02\0");
+        data.extend_from_slice(&(MAX_MOOV_SIZE as u32 + 1).to_be_bytes());
+        data.extend_from_slice(b"moov");
+
+        // Claims the file is big enough to hold it, so only the size limit stops it being read
+        let result = probe_iso(&mut Cursor::new(&data), MAX_MOOV_SIZE * 2);
+        assert_unsupported(result);
+    }
+
+    #[test]
+    fn rejects_mp4_without_moov() {
+        assert_unsupported(probe_iso_bytes(
+            &[
+                iso_box(b"ftyp", b"isom\0\0\x02\0"),
+                iso_box(b"mdat", &[0; 8]),
+            ]
+            .concat(),
+        ));
+    }
+
+    #[test]
+    fn stts_count_is_bounded_by_box() {
+        // Claims far more entries than there are, only the one which is there is counted
+        let info = probe_iso_bytes(&mp4_with_stts(stts(u32::MAX, &[(125, 512)]))).unwrap();
+        assert_eq!(info.frame_rate, Some(25.0));
+
+        // A short entry at the end is ignored
+        let mut short = stts(2, &[(125, 512)]);
+        short.extend_from_slice(&[0, 0, 1]);
+        let len = short.len() as u32;
+        short[..4].copy_from_slice(&len.to_be_bytes());
+        let info = probe_iso_bytes(&mp4_with_stts(short)).unwrap();
+        assert_eq!(info.frame_rate, Some(25.0));
+    }
+
+    #[test]
+    fn malformed_inner_boxes_are_skipped() {
+        // The stts is too short to hold its count, so there's no frame rate but everything else
+        // is still read
+        let data = mp4_with_stts(iso_box(b"stts", &[0, 0]));
+        let info = probe_iso_bytes(&data).unwrap();
+        assert_eq!(info.duration, 5.0);
+        assert_eq!(info.video_codec.as_deref(), Some("avc1"));
+        assert_eq!(info.frame_rate, None);
+    }
+
+    #[test]
+    fn reads_webm() {
+        let info = probe_matroska_bytes(&webm()).unwrap();
+
+        assert_eq!(info.container, "webm");
+        assert_eq!(info.duration, 5.0);
+        assert_eq!(info.video_codec.as_deref(), Some("V_VP9"));
+        assert_eq!((info.width, info.height), (Some(1280), Some(720)));
+        assert_eq!(info.frame_rate, Some(25.0));
+    }
+
+    #[test]
+    fn rejects_truncated_webm() {
+        let data = webm();
+        for len in [0, 3, 10, 30, 45] {
+            assert_unsupported(probe_matroska_bytes(&data[..len]));
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_matroska_element() {
+        // An Info element claiming to be much bigger than the limit
+        let mut info = vec![0x15, 0x49, 0xA9, 0x66, 0x01];
+        info.extend_from_slice(&(MAX_MATROSKA_ELEMENT_SIZE + 1).to_be_bytes()[1..]);
+        let data = webm_with_segment(element(SEGMENT, &info));
+
+        assert_unsupported(probe_matroska_bytes(&data));
+    }
+
+    #[test]
+    fn handles_huge_segment_size() {
+        // The largest known size there is, which runs well past the end of the file
+        let mut data = element(EBML_HEADER, &element(EBML_DOC_TYPE, b"webm"));
+        data.extend_from_slice(&[0x18, 0x53, 0x80, 0x67, 0x01]);
+        data.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE]);
+        data.extend_from_slice(&segment_body());
+
+        let info = probe_matroska_bytes(&data).unwrap();
+        assert_eq!(info.duration, 5.0);
+    }
+
+    #[test]
+    fn rejects_malformed_matroska() {
+        // Not a Matroska document type
+        let data = [
+            element(EBML_HEADER, &element(EBML_DOC_TYPE, b"avi")),
+            element(SEGMENT, &segment_body()),
+        ]
+        .concat();
+        assert_unsupported(probe_matroska_bytes(&data));
+
+        // An ID of all zeroes isn't a valid variable length integer
+        let mut data = webm();
+        let segment = data.len() - segment_body().len() - 12;
+        data[segment] = 0;
+        assert_unsupported(probe_matroska_bytes(&data));
+
+        // Only clusters can have an unknown size
+        let mut segment = vec![0x18, 0x53, 0x80, 0x67, 0xFF];
+        segment.extend_from_slice(&[0x15, 0x49, 0xA9, 0x66, 0xFF]);
+        assert_unsupported(probe_matroska_bytes(&webm_with_segment(segment)));
+
+        // No duration
+        let data = webm_with_segment(element(
+            SEGMENT,
+            &element(INFO, &element(TIMESTAMP_SCALE, &[0x0F, 0x42, 0x40])),
+        ));
+        assert_unsupported(probe_matroska_bytes(&data));
+    }
+}
@@ -0,0 +1,134 @@
+use super::{storage_error, FilmStorage, FilmStream};
+use crate::{data::StorageConfig, error::*};
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt};
+use reqwest::{header, Client};
+use s3::{creds::Credentials, error::S3Error, Bucket, Region};
+use std::{io, path::Path};
+use tokio::fs::{self, File};
+use tracing::{error, info};
+
+/// How long the presigned URLs used to read films are valid for, in seconds
+const PRESIGN_EXPIRY: u32 = 60;
+
+fn s3_error(ex: S3Error) -> Error {
+    error!("S3 error {ex}");
+    Error::InternalError
+}
+
+/// Keeps films in an S3 compatible bucket (AWS, MinIO, ...) under `films/{key}`
+pub struct S3Storage {
+    bucket: Bucket,
+    client: Client,
+}
+
+impl S3Storage {
+    pub fn new(config: &StorageConfig) -> Result<Self, Error> {
+        let StorageConfig {
+            s3_bucket,
+            s3_region,
+            s3_endpoint,
+            s3_access_key,
+            s3_secret_key,
+            s3_path_style,
+            ..
+        } = config;
+
+        let bucket_name = s3_bucket
+            .as_deref()
+            .ok_or_else(|| Error::EnvVarMissing("STORAGE_S3_BUCKET".into()))?;
+
+        let region = match s3_endpoint {
+            Some(endpoint) => Region::Custom {
+                region: s3_region.clone(),
+                endpoint: endpoint.clone(),
+            },
+            None => s3_region.parse().map_err(|ex| {
+                error!("Invalid S3 region {ex}");
+                Error::EnvVarMissing("STORAGE_S3_REGION".into())
+            })?,
+        };
+
+        // Falls back to the usual AWS environment variables and profiles if the keys aren't set
+        let credentials = Credentials::new(
+            s3_access_key.as_deref(),
+            s3_secret_key.as_deref(),
+            None,
+            None,
+            None,
+        )
+        .map_err(|ex| {
+            error!("Couldn't load S3 credentials {ex}");
+            Error::EnvVarMissing("STORAGE_S3_ACCESS_KEY".into())
+        })?;
+
+        let mut bucket = Bucket::new(bucket_name, region, credentials).map_err(s3_error)?;
+        if *s3_path_style {
+            bucket = bucket.with_path_style();
+        }
+
+        info!("Storing films in the S3 bucket {bucket_name}");
+
+        Ok(Self {
+            bucket,
+            client: Client::new(),
+        })
+    }
+
+    fn key(key: &str) -> String {
+        format!("films/{key}")
+    }
+}
+
+#[async_trait]
+impl FilmStorage for S3Storage {
+    async fn store(&self, key: &str, file: &Path) -> Result<(), Error> {
+        let mut reader = File::open(file).await.map_err(storage_error)?;
+
+        // Sent as a multipart upload, so the whole film is never held in memory
+        self.bucket
+            .put_object_stream(&mut reader, Self::key(key))
+            .await
+            .map_err(s3_error)?;
+
+        fs::remove_file(file).await.map_err(storage_error)
+    }
+
+    async fn read(&self, key: &str, start: u64, len: u64) -> Result<FilmStream, Error> {
+        if len == 0 {
+            return Ok(stream::empty().boxed());
+        }
+
+        let url = self
+            .bucket
+            .presign_get(Self::key(key), PRESIGN_EXPIRY, None)
+            .map_err(s3_error)?;
+
+        let response = self
+            .client
+            .get(url)
+            .header(header::RANGE, format!("bytes={start}-{}", start + len - 1))
+            .send()
+            .await
+            .and_then(|x| x.error_for_status())
+            .map_err(|ex| {
+                error!("Couldn't read film from S3 {ex}");
+                Error::InternalError
+            })?;
+
+        Ok(response
+            .bytes_stream()
+            .map(|x| x.map_err(io::Error::other))
+            .boxed())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), Error> {
+        // Deleting a key which doesn't exist succeeds
+        self.bucket
+            .delete_object(Self::key(key))
+            .await
+            .map_err(s3_error)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{
+    data::{SmtpConfig, SmtpSecurity, UrlConfig},
+    db::{Db, QueuedEmail},
+    error::*,
+};
+use actix_web::web::Data;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+mod templates;
+
+pub use templates::Email;
+
+/// How many queued emails to take at a time
+const BATCH_SIZE: i64 = 20;
+
+/// How long to wait between looking for emails to send
+const SEND_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Sends emails through the SMTP server, or just logs them if there isn't one
+pub struct Mailer {
+    transport: Option<AsyncSmtpTransport<Tokio1Executor>>,
+    from: Mailbox,
+    /// For links in the emails
+    urls: UrlConfig,
+}
+
+/// Sends queued emails and reminds teams about deadlines, for as long as the server is running
+pub fn keep_sending(db: Db, mailer: Data<Mailer>, reminder_before: Duration) {
+    tokio::spawn(async move {
+        loop {
+            if !reminder_before.is_zero() {
+                // Already logged, it'll be tried again next time
+                let _ = db.queue_deadline_reminders(reminder_before).await;
+            }
+
+            while let Ok(emails) = db.claim_emails(BATCH_SIZE).await {
+                for email in &emails {
+                    mailer.send_queued(&db, email).await;
+                }
+
+                if (emails.len() as i64) < BATCH_SIZE {
+                    break;
+                }
+            }
+
+            tokio::time::sleep(SEND_INTERVAL).await;
+        }
+    });
+}
+
+impl Mailer {
+    pub fn new(config: &SmtpConfig, urls: UrlConfig) -> Result<Self, Error> {
+        let from = config.from.parse().map_err(|_| {
+            Error::InvalidConfig(format!("SMTP_FROM {} isn't an email address", config.from))
+        })?;
+
+        let transport = match &config.host {
+            Some(host) => {
+                let builder = match config.security {
+                    SmtpSecurity::None => Ok(
+                        AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+                    ),
+                    SmtpSecurity::StartTls => {
+                        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)
+                    }
+                    SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host),
+                }
+                .map_err(|ex| Error::InvalidConfig(format!("Couldn't set up SMTP {ex}")))?;
+
+                let builder = match config.port {
+                    Some(port) => builder.port(port),
+                    None => builder,
+                };
+                let builder = match (&config.username, &config.password) {
+                    (Some(username), Some(password)) => {
+                        builder.credentials(Credentials::new(username.clone(), password.clone()))
+                    }
+                    _ => builder,
+                };
+
+                info!("Sending emails through {host}");
+                Some(builder.build())
+            }
+            None => {
+                warn!("SMTP_HOST isn't set, emails will be logged instead of sent");
+                None
+            }
+        };
+
+        Ok(Self {
+            transport,
+            from,
+            urls,
+        })
+    }
+
+    pub async fn send(&self, to: &str, email: &Email) -> Result<(), Error> {
+        let to: Mailbox = to.parse().map_err(|_| Error::InvalidEmail(to.to_owned()))?;
+        let (subject, body) = email.render(&self.urls);
+
+        let Some(transport) = &self.transport else {
+            info!("Not sending email to {to}, {subject}\n{body}");
+            return Ok(());
+        };
+
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .body(body)
+            .map_err(|ex| {
+                error!("Couldn't build email {ex}");
+                Error::InternalError
+            })?;
+
+        transport.send(message).await.map_err(|ex| {
+            error!("Couldn't send email {ex}");
+            Error::MailUnavailable
+        })?;
+
+        Ok(())
+    }
+
+    /// Sends an email from the queue, then marks it as sent or to be tried again
+    async fn send_queued(&self, db: &Db, queued: &QueuedEmail) {
+        let result = match serde_json::from_value::<Email>(queued.email.clone()) {
+            Ok(email) => self.send(&queued.address, &email).await,
+            Err(ex) => {
+                error!("Email {} doesn't match any template {ex}", queued.id);
+                Err(Error::InternalError)
+            }
+        };
+
+        // Errors marking them are already logged, they'll be tried again once the lease is up
+        let _ = match result {
+            Ok(()) => db.email_sent(queued.id).await,
+            // Trying again won't help these
+            Err(Error::InvalidEmail(_) | Error::InternalError) => {
+                db.email_failed(queued.id, true).await
+            }
+            Err(_) => db.email_failed(queued.id, false).await,
+        };
+    }
+}
@@ -0,0 +1,131 @@
+use crate::{data::UrlConfig, db::Deadline};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The emails the festival sends, with what to fill each one in with. These are what's kept in
+/// the queue, so changing a template changes any waiting to be sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "template", rename_all = "snake_case")]
+pub enum Email {
+    TeamCreated {
+        team_name: String,
+        code: String,
+    },
+    MemberJoined {
+        team_name: String,
+        member: String,
+    },
+    MemberLeft {
+        team_name: String,
+        member: String,
+    },
+    FilmReceived {
+        team_name: String,
+        file_name: String,
+    },
+    DeadlineReminder {
+        team_name: String,
+        deadline: Deadline,
+        at: DateTime<Utc>,
+    },
+    JudgingResults {
+        team_name: String,
+        result: String,
+    },
+    /// Sent straight away rather than queued, so the token is never stored
+    Invite {
+        inviter: String,
+        team_name: String,
+        token: String,
+        expires: DateTime<Utc>,
+    },
+}
+
+fn format_time(time: &DateTime<Utc>) -> String {
+    time.format("%A %-d %B at %H:%M UTC").to_string()
+}
+
+impl Email {
+    /// The subject and body
+    pub fn render(&self, urls: &UrlConfig) -> (String, String) {
+        let UrlConfig { backend, frontend } = urls;
+
+        let (subject, body) = match self {
+            Email::TeamCreated { team_name, code } => (
+                format!("You've created {team_name} for NAFF"),
+                format!(
+                    "Your team {team_name} is ready. Other people can join it with the code \
+                    {code}, or you can invite them from {frontend}/participate.\n"
+                ),
+            ),
+            Email::MemberJoined { team_name, member } => (
+                format!("{member} has joined {team_name}"),
+                format!(
+                    "{member} is now in your team {team_name}. You can see everyone in it at \
+                    {frontend}/participate.\n"
+                ),
+            ),
+            Email::MemberLeft { team_name, member } => (
+                format!("{member} has left {team_name}"),
+                format!(
+                    "{member} isn't in your team {team_name} anymore. You can see everyone still \
+                    in it at {frontend}/participate.\n"
+                ),
+            ),
+            Email::FilmReceived {
+                team_name,
+                file_name,
+            } => (
+                format!("We've got {team_name}'s film"),
+                format!(
+                    "{file_name} has finished uploading for {team_name}. You can check it and \
+                    its details at {frontend}/participate.\n"
+                ),
+            ),
+            Email::DeadlineReminder {
+                team_name,
+                deadline,
+                at,
+            } => (
+                format!("{team_name}, {} closes soon", deadline.describe()),
+                format!(
+                    "Just a reminder, {} for NAFF closes on {}. Head to {frontend}/participate \
+                    before then.\n",
+                    deadline.describe(),
+                    format_time(at)
+                ),
+            ),
+            Email::JudgingResults { team_name, result } => (
+                format!("NAFF results for {team_name}"),
+                format!("The judges have finished, thanks for entering {team_name}.\n\n{result}\n"),
+            ),
+            Email::Invite {
+                inviter,
+                team_name,
+                token,
+                expires,
+            } => {
+                // They might not have an account yet, so there's nothing to opt out of
+                return (
+                    format!("You're invited to join {team_name} for NAFF"),
+                    format!(
+                        "{inviter} has invited you to join their team {team_name} for NAFF.\n\n\
+                        Log in here to join it:\n{backend}/auth/login?invite={token}\n\n\
+                        Log in with this email address, the invite won't work for any other. It \
+                        stops working on {}. If you weren't expecting it, you can ignore this \
+                        email.\n",
+                        format_time(expires)
+                    ),
+                );
+            }
+        };
+
+        (
+            subject,
+            format!(
+                "{body}\n--\nYou're getting this because you're in a team for NAFF. You can stop \
+                these emails at {frontend}/participate.\n"
+            ),
+        )
+    }
+}
@@ -4,18 +4,29 @@ mod api;
 mod auth;
 mod data;
 mod db;
+mod dev_auth;
 mod error;
-mod jwt_helpers;
+mod films;
+mod mail;
+mod oidc;
 
-use crate::{api::auth::auth, data::get_config, db::Db};
+use crate::{
+    api::{auth::auth, dev_auth::dev_auth},
+    data::{get_config, AuthConfig, Config},
+    db::Db,
+    dev_auth::DevIssuer,
+    films::FilmStore,
+    mail::Mailer,
+    oidc::Provider,
+};
 use actix_cors::Cors;
-use actix_web::{web::Data, App, HttpServer};
+use actix_web::{http::header, web::Data, App, HttpServer};
 use db::create_connection;
 use error::AsCreateError;
 pub use error::Error;
 use sqlx::migrate::Migrator;
-use std::{io, process::ExitCode};
-use tracing::{error, info};
+use std::{process::ExitCode, time::Duration};
+use tracing::{error, info, warn};
 use tracing_actix_web::TracingLogger;
 
 static MIGRATOR: Migrator = sqlx::migrate!();
@@ -27,35 +38,93 @@ async fn start() -> Result<(), Error> {
 
     MIGRATOR.run(&pool).await.to_crate()?;
 
-    let (auth0, public) = get_config()?;
+    let Config {
+        auth: auth_config,
+        urls: public,
+        cookies,
+        film,
+        storage,
+        teams,
+        smtp,
+        mail,
+    } = get_config()?;
 
-    let jwk = jwt_helpers::get_kwks(&auth0).await.ok_or_else(|| {
-        error::Error::ServerStartError(io::Error::new(io::ErrorKind::Other, "Could not fetch JWKs"))
-    })?;
+    let film_store = Data::new(FilmStore::new(&storage).await?);
+    let mailer = Data::new(Mailer::new(&smtp, public.clone())?);
 
-    HttpServer::new(move || {
-        App::new()
-            .wrap(
-                Cors::default()
-                    .allowed_headers(["Cookie", "Authorization"])
-                    .allow_any_origin()
-                    .allowed_methods(["GET", "POST"])
-                    .supports_credentials(),
+    db::keep_archiving(
+        Db::new(pool.clone()),
+        Duration::from_secs(teams.archive_after),
+    );
+    mail::keep_sending(
+        Db::new(pool.clone()),
+        mailer.clone(),
+        Duration::from_secs(mail.reminder_before),
+    );
+
+    // Either the OIDC provider's parts or the development issuer get set up, not both
+    let (oidc_config, state_key, provider, dev_issuer) = match auth_config {
+        AuthConfig::Oidc(config) => {
+            let state_key = config.state_key()?;
+
+            let provider = Data::new(Provider::new(config.clone())?);
+            if !provider.refresh().await {
+                warn!(
+                    "Couldn't reach the OIDC provider, nobody can log in until it can be reached"
+                );
+            }
+            oidc::keep_refreshed(provider.clone());
+
+            (
+                Some(Data::new(config)),
+                Some(Data::new(state_key)),
+                Some(provider),
+                None,
             )
-            .wrap(TracingLogger::default())
-            .app_data(Data::new(Db::new(pool.clone())))
-            // .app_data(Data::new(Mutex::new(States::new())))
-            .app_data(Data::new(auth0.clone()))
-            .app_data(Data::new(public.clone()))
-            .app_data(Data::new(jwk.clone()))
-            .service(api::api())
-            .service(auth())
+        }
+        AuthConfig::Dev(config) => (None, None, None, Some(Data::new(DevIssuer::new(&config)))),
+    };
+
+    HttpServer::new(move || {
+        let mut app = App::new();
+        if let Some(dev_issuer) = &dev_issuer {
+            app = app.app_data(dev_issuer.clone());
+        }
+        if let (Some(oidc_config), Some(state_key), Some(provider)) =
+            (&oidc_config, &state_key, &provider)
+        {
+            app = app
+                .app_data(oidc_config.clone())
+                .app_data(state_key.clone())
+                .app_data(provider.clone());
+        }
+
+        app.wrap(
+            Cors::default()
+                .allowed_headers([header::COOKIE, header::AUTHORIZATION, header::CONTENT_TYPE])
+                .allow_any_origin()
+                .allowed_methods(["GET", "POST"])
+                .supports_credentials(),
+        )
+        .wrap(TracingLogger::default())
+        .app_data(Data::new(Db::new(pool.clone())))
+        .app_data(Data::new(public.clone()))
+        .app_data(Data::new(cookies.clone()))
+        .app_data(Data::new(film.clone()))
+        .app_data(film_store.clone())
+        .app_data(mailer.clone())
+        .service(api::api())
+        .service(if dev_issuer.is_some() {
+            dev_auth()
+        } else {
+            auth()
+        })
     })
     .bind("0.0.0.0:8080")
-    .map_err(|ex| Error::ServerStartError(ex))?
+    .map_err(Error::ServerStartError)?
     .run()
     .await
-    .map_err(|ex| Error::ServerStartError(ex))?;
+    .map_err(Error::ServerStartError)?;
 
     Ok(())
 }
@@ -72,7 +141,7 @@ fn main() -> ExitCode {
         .init();
     #[allow(clippy::expect_used, clippy::diverging_sub_expression)]
     {
-        return match tokio::runtime::Builder::new_multi_thread()
+        match tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
         {
@@ -88,6 +157,6 @@ fn main() -> ExitCode {
                     val.to_code()
                 })
                 .unwrap_or(ExitCode::SUCCESS),
-        };
+        }
     }
 }
@@ -0,0 +1,301 @@
+use actix_web::web::Data;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use reqwest::{Client, Url};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    io,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info, warn};
+
+use crate::{data::OidcConfig, error::*};
+
+/// How often the provider is checked for changes, to pick up rotated keys
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long to wait after a failed fetch before trying again
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// Shortest time between fetches caused by requests, e.g. tokens signed with a key we don't know
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// Longest to wait to connect to the provider
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest a request to the provider can take, so a provider which hangs can't hold up logins
+/// and session refreshes indefinitely
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The parts of the provider's discovery document we use
+#[derive(Deserialize, Clone, Debug)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+    /// Where to send people to log out of the provider too
+    pub end_session_endpoint: Option<String>,
+    /// Defaults to RS256, which every provider has to support
+    #[serde(default = "default_signing_algs")]
+    pub id_token_signing_alg_values_supported: Vec<String>,
+}
+
+/// What the token endpoint gives back, for both authorization codes and refresh tokens
+#[derive(Deserialize, Clone)]
+pub struct TokenResponse {
+    pub id_token: String,
+    pub expires_in: i64,
+    pub refresh_token: Option<String>,
+}
+
+fn default_signing_algs() -> Vec<String> {
+    vec!["RS256".into()]
+}
+
+async fn get_json<T: DeserializeOwned>(client: &Client, url: &str) -> Option<T> {
+    debug!("Fetching {url}");
+    let result = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|x| x.error_for_status())
+        .map_err(|ex| error!("Couldn't fetch {url} {ex}"))
+        .ok()?
+        .json()
+        .await;
+
+    match result {
+        Err(ex) => {
+            error!("Invalid response from {url} {ex}");
+            None
+        }
+        Ok(x) => Some(x),
+    }
+}
+
+async fn discover(client: &Client, issuer: &str) -> Option<ProviderMetadata> {
+    let metadata: ProviderMetadata = get_json(
+        client,
+        &format!(
+            "{}/.well-known/openid-configuration",
+            issuer.trim_end_matches('/')
+        ),
+    )
+    .await?;
+
+    if metadata.issuer.trim_end_matches('/') != issuer.trim_end_matches('/') {
+        error!(
+            "The provider says its issuer is {}, not {issuer}",
+            metadata.issuer
+        );
+        return None;
+    }
+
+    Some(metadata)
+}
+
+/// The OpenID Connect provider people log in with. Its discovery document and signing keys are
+/// fetched lazily and kept up to date as keys are rotated.
+pub struct Provider {
+    config: OidcConfig,
+    client: Client,
+    metadata: RwLock<Option<ProviderMetadata>>,
+    keys: RwLock<JwkSet>,
+    /// When the provider was last fetched, locked while fetching so only one fetch happens at a time
+    last_fetch: Mutex<Option<Instant>>,
+}
+
+impl Provider {
+    /// Starts knowing nothing about the provider, it has to be fetched with [`Provider::refresh`]
+    pub fn new(config: OidcConfig) -> Result<Self, Error> {
+        let client = Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|ex| Error::ServerStartError(io::Error::other(ex)))?;
+
+        Ok(Self {
+            config,
+            client,
+            metadata: RwLock::new(None),
+            keys: RwLock::new(JwkSet { keys: vec![] }),
+            last_fetch: Mutex::new(None),
+        })
+    }
+
+    fn cached_key(&self, kid: &str) -> Option<Jwk> {
+        self.keys.read().unwrap().find(kid).cloned()
+    }
+
+    fn cached_metadata(&self) -> Option<ProviderMetadata> {
+        self.metadata.read().unwrap().clone()
+    }
+
+    fn is_working(&self) -> bool {
+        self.metadata.read().unwrap().is_some() && !self.keys.read().unwrap().keys.is_empty()
+    }
+
+    async fn fetch(&self, last_fetch: &mut Option<Instant>) -> bool {
+        *last_fetch = Some(Instant::now());
+
+        let Some(metadata) = discover(&self.client, &self.config.issuer).await else {
+            return false;
+        };
+        let keys = get_json::<JwkSet>(&self.client, &metadata.jwks_uri).await;
+        *self.metadata.write().unwrap() = Some(metadata);
+
+        match keys {
+            Some(keys) => {
+                *self.keys.write().unwrap() = keys;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Fetches the discovery document and keys, keeping the old ones if it fails. Returns if it
+    /// worked.
+    pub async fn refresh(&self) -> bool {
+        let mut last_fetch = self.last_fetch.lock().await;
+        self.fetch(&mut last_fetch).await
+    }
+
+    /// Refreshes, unless it was done very recently, so bad tokens can't be used to hammer the
+    /// provider
+    async fn refresh_if_stale(&self) {
+        let mut last_fetch = self.last_fetch.lock().await;
+
+        if last_fetch
+            .map(|x| x.elapsed() >= MIN_REFRESH_INTERVAL)
+            .unwrap_or(true)
+        {
+            self.fetch(&mut last_fetch).await;
+        }
+    }
+
+    /// Where to send someone so they are logged out of the provider as well as us, coming back to
+    /// `return_to` afterwards. Providers which don't advertise a logout endpoint need
+    /// `OIDC_LOGOUT_URL` set, or people are only logged out of the site.
+    pub async fn logout_url(&self, return_to: &str, id_token: Option<&str>) -> Option<Url> {
+        let client_id = self.config.client_id.as_str();
+
+        // Auth0's own logout endpoint, and others like it, take `returnTo`
+        let result = if let Some(logout_url) = &self.config.logout_url {
+            Url::parse_with_params(
+                logout_url,
+                [("client_id", client_id), ("returnTo", return_to)],
+            )
+        } else {
+            let endpoint = self.metadata().await.ok()?.end_session_endpoint?;
+            let mut params = vec![
+                ("client_id", client_id),
+                ("post_logout_redirect_uri", return_to),
+            ];
+            if let Some(id_token) = id_token {
+                params.push(("id_token_hint", id_token));
+            }
+
+            Url::parse_with_params(&endpoint, params)
+        };
+
+        result
+            .map_err(|ex| error!("Invalid logout endpoint {ex}"))
+            .ok()
+    }
+
+    pub async fn metadata(&self) -> Result<ProviderMetadata, Error> {
+        if let Some(metadata) = self.cached_metadata() {
+            return Ok(metadata);
+        }
+
+        self.refresh_if_stale().await;
+
+        self.cached_metadata().ok_or_else(|| {
+            warn!("The provider hasn't been discovered yet");
+            Error::AuthUnavailable
+        })
+    }
+
+    /// Exchanges a grant (an authorization code or refresh token) at the token endpoint. Grants the
+    /// provider refuses give [`Error::Unauthorized`].
+    pub async fn request_tokens(&self, grant: &[(&str, &str)]) -> Result<TokenResponse, Error> {
+        let metadata = self.metadata().await?;
+
+        let mut params = vec![
+            ("client_id", self.config.client_id.as_str()),
+            ("client_secret", self.config.client_secret.as_str()),
+        ];
+        params.extend_from_slice(grant);
+
+        let response = self
+            .client
+            .post(&metadata.token_endpoint)
+            .form(&params)
+            .send()
+            .await
+            .map_err(|ex| {
+                error!("Couldn't reach the token endpoint {ex}");
+                Error::AuthUnavailable
+            })?;
+
+        if response.status().is_client_error() {
+            debug!(
+                "Token request was refused: {}",
+                response.text().await.unwrap_or_default()
+            );
+            return Err(Error::Unauthorized);
+        }
+
+        response
+            .error_for_status()
+            .map_err(|ex| {
+                error!("Token endpoint failed {ex}");
+                Error::AuthUnavailable
+            })?
+            .json()
+            .await
+            .map_err(|ex| {
+                error!("Invalid token response {ex}");
+                Error::AuthUnavailable
+            })
+    }
+
+    /// Finds the key with the id `kid`. Keys we haven't seen cause a refresh.
+    pub async fn find(&self, kid: &str) -> Result<Jwk, Error> {
+        if let Some(jwk) = self.cached_key(kid) {
+            return Ok(jwk);
+        }
+
+        info!("Refreshing JWKs for unknown key {kid}");
+        self.refresh_if_stale().await;
+
+        self.cached_key(kid).ok_or_else(|| {
+            if self.is_working() {
+                warn!("Could not find JWKs");
+                Error::Unauthorized
+            } else {
+                warn!("No JWKs have been fetched yet");
+                Error::AuthUnavailable
+            }
+        })
+    }
+}
+
+/// Refreshes the provider in the background, retrying sooner while the fetches are failing
+pub fn keep_refreshed(provider: Data<Provider>) {
+    tokio::spawn(async move {
+        let mut working = provider.is_working();
+
+        loop {
+            tokio::time::sleep(if working {
+                REFRESH_INTERVAL
+            } else {
+                RETRY_INTERVAL
+            })
+            .await;
+
+            working = provider.refresh().await;
+            if !working {
+                warn!("Couldn't refresh the OIDC provider, trying again in {RETRY_INTERVAL:?}");
+            }
+        }
+    });
+}
@@ -15,15 +15,37 @@ export type User = {
 	id: string;
 	name: string;
 	email: string;
-	is_admin: boolean;
+	disabled: boolean;
+	/** They don't want emails about their team */
+	email_opt_out: boolean;
+	permissions: Permission[];
 };
 
+export type Permission = 'view_teams' | 'edit_teams' | 'manage_festival' | 'manage_users';
+
 export type Team = {
 	id: string;
 	name: string;
 	film_name: string;
 	film_descriptions: string;
 	has_file: boolean;
+	submitted: boolean;
+	category: string | null;
+	captain: string | null;
+	emptied: string | null;
+	archived: string | null;
+	approval_required: boolean;
+};
+
+/** The user's team, or the one they've asked to join if `pending` */
+export type CurrentTeam = Team & { pending: boolean };
+
+export type JoinCode = {
+	code: string;
+	team: string;
+	expires: string | null;
+	single_use: boolean;
+	created: string;
 };
 
 export const getUser = async (options?: { fetch: typeof fetch; token: string }): Promise<User> => {
@@ -43,10 +65,24 @@ export const getUser = async (options?: { fetch: typeof fetch; token: string }):
 	return requestJson;
 };
 
+export const setEmailOptOut = async (optOut: boolean): Promise<void> => {
+	let response = await fetch(`${PUBLIC_BACKEND}/api/user/email`, {
+		method: 'POST',
+		credentials: 'include',
+		headers: { 'Content-Type': 'application/json' },
+		body: JSON.stringify({ opt_out: optOut })
+	});
+	let responseJson = await response.json();
+
+	if (response.status != 200) {
+		throw new ApiError(responseJson);
+	}
+};
+
 export const getTeam = async (options?: {
 	fetch: typeof fetch;
 	token: string;
-}): Promise<Team | null> => {
+}): Promise<CurrentTeam | null> => {
 	let fetch_options: RequestInit = {
 		credentials: 'include',
 		headers: { ...(options ? { Authorization: options.token } : {}) }
@@ -86,21 +122,57 @@ export const getMembers = async (
 	return requestJson;
 };
 
-export const joinTeam = async (code: string): Promise<Team> => {
-	let response = await fetch(`${PUBLIC_BACKEND}/api/team/join?id=${code}`, {
+export const getJoinCode = async (
+	teamId: string,
+	options?: {
+		fetch: typeof fetch;
+		token: string;
+	}
+): Promise<JoinCode | null> => {
+	let fetch_options: RequestInit = {
+		credentials: 'include',
+		headers: { ...(options ? { Authorization: options.token } : {}) }
+	};
+
+	const fetch_fn = options?.fetch ?? fetch;
+	let request = await fetch_fn(`${PUBLIC_BACKEND}/api/team/${teamId}/code`, fetch_options);
+	let requestJson = await request.json();
+
+	if (request.status != 200) {
+		throw new ApiError(requestJson);
+	}
+
+	return requestJson;
+};
+
+export const joinTeam = async (code: string): Promise<CurrentTeam> => {
+	let response = await fetch(`${PUBLIC_BACKEND}/api/team/join?code=${encodeURIComponent(code)}`, {
 		method: 'POST',
 		credentials: 'include'
 	});
 	// The API will always return a JSON response, unless you fuck'd up real bad
 	let responseJson = await response.json();
 
-	if (response.status != 200) {
+	// 202 means the captain has to accept them first
+	if (response.status != 200 && response.status != 202) {
 		throw new ApiError(responseJson);
 	}
 
 	return responseJson;
 };
 
+export const cancelJoinRequest = async (): Promise<void> => {
+	let response = await fetch(`${PUBLIC_BACKEND}/api/team/join/cancel`, {
+		method: 'POST',
+		credentials: 'include'
+	});
+	let responseJson = await response.json();
+
+	if (response.status != 200) {
+		throw new ApiError(responseJson);
+	}
+};
+
 export const createTeam = async (code: string): Promise<Team> => {
 	let response = await fetch(`${PUBLIC_BACKEND}/api/team/new?name=${code}`, {
 		method: 'POST',
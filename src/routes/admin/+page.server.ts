@@ -1,12 +1,14 @@
 import type { PageServerLoad } from './$types';
 import { error, redirect } from '@sveltejs/kit';
 import { PUBLIC_BACKEND } from '$env/static/public';
+import { env } from '$env/dynamic/public';
 import * as api from '$lib/client/api';
 
 export const prerender = false;
 
 export const load: PageServerLoad = async ({ cookies, fetch }) => {
-	const auth = cookies.get('access_token');
+	const session = cookies.get(env.PUBLIC_SESSION_COOKIE ?? 'session');
+	const auth = session ? `Session ${session}` : cookies.get('access_token');
 	console.log(auth);
 
 	if (!auth || auth == '') {
@@ -33,7 +35,7 @@ export const load: PageServerLoad = async ({ cookies, fetch }) => {
 		});
 	}
 
-	if (!user.is_admin) {
+	if (!user.permissions.includes('view_teams')) {
 		throw redirect(307, '/participate');
 	}
 
@@ -1,12 +1,26 @@
 import type { PageServerLoad } from './$types';
 import { error, redirect } from '@sveltejs/kit';
 import { PUBLIC_BACKEND } from '$env/static/public';
+import { env } from '$env/dynamic/public';
 import * as api from '$lib/client/api';
 
 export const prerender = false;
 
-export const load: PageServerLoad = async ({ cookies, fetch }) => {
-	const auth = cookies.get('access_token');
+// Why an invite couldn't be accepted when logging in, by error code
+const inviteErrors: Record<string, string> = {
+	'202': 'Verify your email address with your login provider, then use the invite again',
+	'206': 'The invite was sent to a different email address, log in with that address to accept it',
+	'207': 'The invite has expired or been revoked, ask your captain for a new one',
+	'209': 'The team you were invited to is full',
+	'222': "You can't be in the team you were invited to because of its category",
+	'226': "Registration isn't open yet, use the invite again once it opens",
+	'227': 'Registration has closed',
+	'241': "You're already in a team, leave it before accepting the invite"
+};
+
+export const load: PageServerLoad = async ({ cookies, fetch, url }) => {
+	const session = cookies.get(env.PUBLIC_SESSION_COOKIE ?? 'session');
+	const auth = session ? `Session ${session}` : cookies.get('access_token');
 
 	if (!auth || auth == '') {
 		throw redirect(307, `${PUBLIC_BACKEND}/auth/login`);
@@ -21,7 +35,7 @@ export const load: PageServerLoad = async ({ cookies, fetch }) => {
 		user = await api.getUser({ fetch, token: auth });
 		team = await api.getTeam({ fetch, token: auth });
 
-		if (team) {
+		if (team && !team.pending) {
 			members = await api.getMembers(team.id, { fetch, token: auth });
 		}
 	} catch (ex) {
@@ -30,6 +44,9 @@ export const load: PageServerLoad = async ({ cookies, fetch }) => {
 			if (ex.code == 243) {
 				throw redirect(307, `${PUBLIC_BACKEND}/auth/logout`);
 			}
+			if (ex.code == 214) {
+				throw error(403, { message: ex.message });
+			}
 		}
 
 		console.error(ex);
@@ -39,13 +56,16 @@ export const load: PageServerLoad = async ({ cookies, fetch }) => {
 		});
 	}
 
-	if (user.is_admin) {
+	if (user.permissions.includes('view_teams')) {
 		throw redirect(307, '/admin');
 	}
 
+	const inviteError = url.searchParams.get('invite_error');
+
 	return {
 		user,
 		team,
-		members
+		members,
+		inviteError: inviteError ? inviteErrors[inviteError] ?? "Couldn't accept the invite" : null
 	};
 };